@@ -0,0 +1,101 @@
+//! Timings of the operations with a fast path against the naive way of doing the same, run with
+//! `cargo bench`.
+
+use sketches_rust::{DDSketch, Store, UnboundedSizeDenseStore};
+use std::hint::black_box;
+use std::time::Instant;
+
+fn time<R>(name: &str, iterations: u32, mut f: impl FnMut() -> R) {
+    black_box(f());
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    println!("{:<48} {:>12.2?}", name, start.elapsed() / iterations);
+}
+
+fn bench_merge() {
+    let mut source = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut other = UnboundedSizeDenseStore::new();
+    // A store of another count type, whose bins go through the generic merge.
+    let mut other_f32 = UnboundedSizeDenseStore::<f32>::default();
+    for i in 0..2000 {
+        let value = 1.01f64.powi(i);
+        source.accept(value);
+        other.add(i, 1.0);
+        other_f32.add(i, 1.0);
+    }
+    let mut target = UnboundedSizeDenseStore::new();
+    target.add(0, 1.0);
+
+    time("store merge, bin by bin", 2000, || {
+        let mut store = target.clone();
+        for bin in other.get_descending_stream() {
+            store.add_bin(bin);
+        }
+        store
+    });
+    time("store merge_with, cross config", 2000, || {
+        let mut store = target.clone();
+        store.merge_with(&mut other_f32);
+        store
+    });
+    time("store merge_with, same config", 2000, || {
+        let mut store = target.clone();
+        store.merge_with(&mut other);
+        store
+    });
+    time("sketch merge_with, same config", 2000, || {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        sketch.accept(1.0);
+        sketch.merge_with(&mut source).unwrap();
+        sketch
+    });
+}
+
+fn bench_frozen_quantiles() {
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 0..100_000 {
+        sketch.accept(i as f64);
+    }
+    let frozen = sketch.freeze();
+    let quantiles: Vec<f64> = (0..=1000).map(|i| i as f64 / 1000.0).collect();
+
+    time("1001 quantiles, live sketch", 100, || {
+        quantiles
+            .iter()
+            .map(|&quantile| sketch.get_value_at_quantile(quantile))
+            .collect::<Vec<_>>()
+    });
+    time("1001 quantiles, frozen sketch", 100, || {
+        quantiles
+            .iter()
+            .map(|&quantile| frozen.get_value_at_quantile(quantile))
+            .collect::<Vec<_>>()
+    });
+}
+
+fn bench_insert_many() {
+    let values: Vec<f64> = (0..100_000)
+        .map(|i| ((i * 7919) % 100_000) as f64 - 20_000.0)
+        .collect();
+
+    time("100000 values, accept one by one", 50, || {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        for &value in &values {
+            sketch.accept(value);
+        }
+        sketch
+    });
+    time("100000 values, insert_many", 50, || {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        sketch.insert_many(&values);
+        sketch
+    });
+}
+
+fn main() {
+    bench_merge();
+    bench_frozen_quantiles();
+    bench_insert_many();
+}
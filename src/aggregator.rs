@@ -0,0 +1,31 @@
+use crate::error::Error;
+use crate::index_mapping::IndexMapping;
+use crate::sketch::DDSketch;
+use crate::store::Store;
+
+/// Merges encoded sketches one at a time into a single accumulator sketch.
+pub struct SketchAggregator<I: IndexMapping, S: Store> {
+    sketch: DDSketch<I, S>,
+}
+
+impl<I: IndexMapping, S: Store> SketchAggregator<I, S> {
+    pub fn new(sketch: DDSketch<I, S>) -> SketchAggregator<I, S> {
+        SketchAggregator { sketch }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.sketch.decode_slice_and_merge_with(bytes)
+    }
+
+    pub fn quantile(&mut self, quantile: f64) -> Option<f64> {
+        self.sketch.get_value_at_quantile(quantile)
+    }
+
+    pub fn get_count(&mut self) -> f64 {
+        self.sketch.get_count()
+    }
+
+    pub fn into_sketch(self) -> DDSketch<I, S> {
+        self.sketch
+    }
+}
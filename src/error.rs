@@ -4,6 +4,8 @@ use std::{fmt, io};
 pub enum Error {
     InvalidArgument(&'static str),
     IoError(io::ErrorKind),
+    /// The serialization version byte of an encoding this crate cannot decode.
+    UnsupportedVersion(u8),
 }
 
 impl fmt::Display for Error {
@@ -11,6 +13,9 @@ impl fmt::Display for Error {
         match *self {
             Error::InvalidArgument(arg) => write!(f, "Invalid argument: {}", arg),
             Error::IoError(ref cause) => write!(f, "Io Error: {}", cause),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported serialization version: {}", version)
+            }
         }
     }
 }
@@ -1,16 +1,28 @@
 use super::*;
 use crate::index_mapping::IndexMapping;
 use crate::serde;
+use std::fmt;
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct CubicallyInterpolatedMapping {
     gamma: f64,
     index_offset: f64,
     multiplier: f64,
     relative_accuracy: f64,
+    representative_value: RepresentativeValue,
 }
 
 impl CubicallyInterpolatedMapping {
+    /// Sets which point of a bin `value` returns.
+    pub fn with_representative_value(mut self, representative_value: RepresentativeValue) -> Self {
+        self.representative_value = representative_value;
+        self
+    }
+
+    pub fn representative_value(&self) -> RepresentativeValue {
+        self.representative_value
+    }
+
     const A: f64 = 6.0 / 35.0;
     const B: f64 = -3.0 / 5.0;
     const C: f64 = 10.0 / 7.0;
@@ -75,7 +87,15 @@ impl IndexMapping for CubicallyInterpolatedMapping {
     }
 
     fn value(&self, index: i32) -> f64 {
-        self.lower_bound(index) * (1.0 + self.relative_accuracy)
+        match self.representative_value {
+            RepresentativeValue::RelativeAccuracy => {
+                self.lower_bound(index) * (1.0 + self.relative_accuracy)
+            }
+            RepresentativeValue::LowerBound => self.lower_bound(index),
+            RepresentativeValue::GeometricMidpoint => {
+                (self.lower_bound(index) * self.upper_bound(index)).sqrt()
+            }
+        }
     }
 
     fn lower_bound(&self, index: i32) -> f64 {
@@ -101,7 +121,7 @@ impl IndexMapping for CubicallyInterpolatedMapping {
     }
 
     fn max_indexable_value(&self) -> f64 {
-        f64::max(
+        f64::min(
             f64::powf(
                 2.0,
                 (i32::MAX as f64 - self.index_offset) / self.multiplier - 1.0,
@@ -132,6 +152,7 @@ impl IndexMapping for CubicallyInterpolatedMapping {
             gamma,
             index_offset,
             multiplier,
+            representative_value: RepresentativeValue::default(),
         })
     }
 
@@ -147,13 +168,22 @@ impl IndexMapping for CubicallyInterpolatedMapping {
             gamma,
             index_offset,
             multiplier,
+            representative_value: RepresentativeValue::default(),
         })
     }
 }
 
-impl ToString for CubicallyInterpolatedMapping {
-    fn to_string(&self) -> String {
-        format!(
+/// Compares gamma and index offset up to rounding errors, see [`IndexMapping::same_mapping`].
+impl PartialEq for CubicallyInterpolatedMapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.same_mapping(other)
+    }
+}
+
+impl fmt::Display for CubicallyInterpolatedMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "CubicallyInterpolatedMapping{{gamma:{},indexOffset: {}}}",
             self.gamma, self.index_offset
         )
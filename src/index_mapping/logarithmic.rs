@@ -1,15 +1,27 @@
 use super::*;
 use crate::index_mapping::IndexMapping;
+use std::fmt;
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct LogarithmicMapping {
     gamma: f64,
     index_offset: f64,
     multiplier: f64,
     relative_accuracy: f64,
+    representative_value: RepresentativeValue,
 }
 
 impl LogarithmicMapping {
+    /// Sets which point of a bin `value` returns.
+    pub fn with_representative_value(mut self, representative_value: RepresentativeValue) -> Self {
+        self.representative_value = representative_value;
+        self
+    }
+
+    pub fn representative_value(&self) -> RepresentativeValue {
+        self.representative_value
+    }
+
     const CORRECTING_FACTOR: f64 = 1.0;
     const BASE: f64 = std::f64::consts::E;
 
@@ -45,7 +57,15 @@ impl IndexMapping for LogarithmicMapping {
     }
 
     fn value(&self, index: i32) -> f64 {
-        self.lower_bound(index) * (1.0 + self.relative_accuracy)
+        match self.representative_value {
+            RepresentativeValue::RelativeAccuracy => {
+                self.lower_bound(index) * (1.0 + self.relative_accuracy)
+            }
+            RepresentativeValue::LowerBound => self.lower_bound(index),
+            RepresentativeValue::GeometricMidpoint => {
+                (self.lower_bound(index) * self.upper_bound(index)).sqrt()
+            }
+        }
     }
 
     fn lower_bound(&self, index: i32) -> f64 {
@@ -71,7 +91,7 @@ impl IndexMapping for LogarithmicMapping {
     }
 
     fn max_indexable_value(&self) -> f64 {
-        f64::max(
+        f64::min(
             f64::powf(
                 2.0,
                 (i32::MAX as f64 - self.index_offset) / self.multiplier - 1.0,
@@ -96,6 +116,7 @@ impl IndexMapping for LogarithmicMapping {
             gamma,
             index_offset,
             multiplier,
+            representative_value: RepresentativeValue::default(),
         })
     }
 
@@ -108,13 +129,22 @@ impl IndexMapping for LogarithmicMapping {
             gamma,
             index_offset,
             multiplier,
+            representative_value: RepresentativeValue::default(),
         })
     }
 }
 
-impl ToString for LogarithmicMapping {
-    fn to_string(&self) -> String {
-        format!(
+/// Compares gamma and index offset up to rounding errors, see [`IndexMapping::same_mapping`].
+impl PartialEq for LogarithmicMapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.same_mapping(other)
+    }
+}
+
+impl fmt::Display for LogarithmicMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "LogarithmicMapping{{gamma:{},indexOffset: {}}}",
             self.gamma, self.index_offset
         )
@@ -19,6 +19,41 @@ pub trait IndexMapping: ToString + Sized {
     fn get_relative_accuracy(&self) -> f64;
     fn min_indexable_value(&self) -> f64;
     fn max_indexable_value(&self) -> f64;
+    /// Like `index` but clamps the value to the indexable range first, so that huge values,
+    /// infinities and values too small to index (including `NaN`) map to the edge indexes instead of
+    /// to indexes near `i32::MAX` or `i32::MIN` that overflow store arithmetic.
+    fn saturating_index(&self, value: f64) -> i32 {
+        let min_value = self.min_indexable_value();
+        let max_value = self.max_indexable_value();
+        if value.is_nan() || value <= min_value {
+            self.index(min_value)
+        } else if value >= max_value {
+            self.index(max_value)
+        } else {
+            self.index(value)
+        }
+    }
+    /// Whether `other` has the same layout and, up to rounding errors, the same gamma and index
+    /// offset, so that both map values to the same indexes.
+    fn same_mapping(&self, other: &impl IndexMapping) -> bool {
+        self.layout() as u8 == other.layout() as u8
+            && nearly_equal(self.gamma(), other.gamma())
+            && nearly_equal(self.index_offset(), other.index_offset())
+    }
+    fn index_range(&self, min_value: f64, max_value: f64) -> (i32, i32) {
+        (self.index(min_value), self.index(max_value))
+    }
+    /// The relative error of mapping the magnitude of `value` to its bin and back, `NaN` if the
+    /// magnitude is outside the indexable range, as 0.0 is. It is at most `get_relative_accuracy`
+    /// with the default [`RepresentativeValue`], other representative values reach about twice
+    /// that.
+    fn check_relative_accuracy(&self, value: f64) -> f64 {
+        let magnitude = value.abs();
+        if !(self.min_indexable_value()..=self.max_indexable_value()).contains(&magnitude) {
+            return f64::NAN;
+        }
+        (magnitude - self.value(self.index(magnitude))).abs() / magnitude
+    }
     fn with_relative_accuracy(relative_accuracy: f64) -> Result<Self, Error>;
     fn with_gamma_offset(gamma: f64, index_offset: f64) -> Result<Self, Error>;
     fn encode(&self, output: &mut impl Output) -> Result<(), Error> {
@@ -29,6 +64,18 @@ pub trait IndexMapping: ToString + Sized {
     }
 }
 
+/// Which point of its bin `IndexMapping::value` returns for an index.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum RepresentativeValue {
+    /// `lower_bound * (1 + relative_accuracy)`, which is within the relative accuracy of every value
+    /// of the bin.
+    #[default]
+    RelativeAccuracy,
+    LowerBound,
+    /// `sqrt(lower_bound * upper_bound)`.
+    GeometricMidpoint,
+}
+
 pub enum IndexMappingLayout {
     LOG = 0,
     LogLinear = 1,
@@ -56,6 +103,25 @@ impl IndexMappingLayout {
     }
 }
 
+pub fn planned_bin_count(
+    relative_accuracy: f64,
+    min_value: f64,
+    max_value: f64,
+) -> Result<usize, Error> {
+    if min_value <= 0.0 || min_value > max_value {
+        return Err(Error::InvalidArgument(
+            "The value range must be positive and ordered.",
+        ));
+    }
+    let index_mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy)?;
+    let (min_index, max_index) = index_mapping.index_range(min_value, max_value);
+    Ok((max_index as i64 - min_index as i64 + 1) as usize)
+}
+
+fn nearly_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() <= 1e-12 * f64::max(1.0, f64::max(a.abs(), b.abs()))
+}
+
 fn calculate_relative_accuracy(gamma: f64, correcting_factor: f64) -> f64 {
     let exact_log_gamma = gamma.powf(correcting_factor);
     (exact_log_gamma - 1.0) / (exact_log_gamma + 1.0)
@@ -68,7 +134,11 @@ fn calculate_gamma(relative_accuracy: f64, correcting_factor: f64) -> f64 {
 
 #[cfg(test)]
 mod tests {
-    use crate::index_mapping::{CubicallyInterpolatedMapping, IndexMapping, LogarithmicMapping};
+    use crate::index_mapping::{
+        planned_bin_count, CubicallyInterpolatedMapping, IndexMapping, LogarithmicMapping,
+        RepresentativeValue,
+    };
+    use crate::store::{Store, UnboundedSizeDenseStore, MAX_INDEX};
 
     const TEST_GAMMAS: [f64; 3] = [1.0 + 1e-6, 1.02, 1.5];
     const TEST_INDEX_OFFSETS: [f64; 4] = [0.0, 1.0, -12.23, 7768.3];
@@ -277,4 +347,183 @@ mod tests {
             assert_eq!(indexes[i], mapping.index(values[i]));
         }
     }
+
+    #[test]
+    fn test_saturating_index() {
+        for gamma in [1.0 + 1e-9, 1.02] {
+            let logarithmic = LogarithmicMapping::with_gamma_offset(gamma, 0.0).unwrap();
+            let cubic = CubicallyInterpolatedMapping::with_gamma_offset(gamma, 0.0).unwrap();
+            assert_saturating_index(&logarithmic);
+            assert_saturating_index(&cubic);
+        }
+    }
+
+    fn assert_saturating_index(mapping: &impl IndexMapping) {
+        let max_index = mapping.index(mapping.max_indexable_value());
+        let min_index = mapping.index(mapping.min_indexable_value());
+        assert!(min_index > i32::MIN && max_index < i32::MAX);
+        assert_eq!(max_index, mapping.saturating_index(f64::MAX));
+        assert_eq!(max_index, mapping.saturating_index(f64::INFINITY));
+        assert_eq!(min_index, mapping.saturating_index(f64::MIN_POSITIVE));
+        assert_eq!(min_index, mapping.saturating_index(0.0));
+        assert_eq!(min_index, mapping.saturating_index(f64::NAN));
+        assert_eq!(mapping.index(1.5), mapping.saturating_index(1.5));
+
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(mapping.saturating_index(f64::MAX), 1.0);
+        store.add(mapping.saturating_index(f64::MAX), 1.0);
+        assert_eq!(
+            vec![(max_index.min(MAX_INDEX), 2.0)],
+            store.get_ascending_stream()
+        );
+    }
+
+    #[test]
+    fn test_representative_value() {
+        let default = LogarithmicMapping::with_relative_accuracy(1e-2).unwrap();
+        assert_eq!(
+            RepresentativeValue::RelativeAccuracy,
+            default.representative_value()
+        );
+        assert_eq!(
+            default.lower_bound(42) * (1.0 + default.get_relative_accuracy()),
+            default.value(42)
+        );
+        for representative_value in [
+            RepresentativeValue::RelativeAccuracy,
+            RepresentativeValue::LowerBound,
+            RepresentativeValue::GeometricMidpoint,
+        ] {
+            let logarithmic = LogarithmicMapping::with_relative_accuracy(1e-2)
+                .unwrap()
+                .with_representative_value(representative_value);
+            let cubic = CubicallyInterpolatedMapping::with_relative_accuracy(1e-2)
+                .unwrap()
+                .with_representative_value(representative_value);
+            for index in -500..500 {
+                assert_representative_in_bin(&logarithmic, index);
+                assert_representative_in_bin(&cubic, index);
+            }
+        }
+        let lower = CubicallyInterpolatedMapping::with_relative_accuracy(1e-2)
+            .unwrap()
+            .with_representative_value(RepresentativeValue::LowerBound);
+        assert_eq!(lower.lower_bound(7), lower.value(7));
+    }
+
+    fn assert_representative_in_bin(mapping: &impl IndexMapping, index: i32) {
+        let value = mapping.value(index);
+        assert!(mapping.lower_bound(index) <= value && value < mapping.upper_bound(index));
+    }
+
+    #[test]
+    fn test_same_mapping() {
+        let mapping = LogarithmicMapping::with_relative_accuracy(1e-2).unwrap();
+        assert_eq!(
+            mapping,
+            LogarithmicMapping::with_relative_accuracy(1e-2).unwrap()
+        );
+        let reconstructed =
+            LogarithmicMapping::with_gamma_offset(mapping.gamma() * (1.0 + 1e-15), 1e-14).unwrap();
+        assert_eq!(mapping, reconstructed);
+        assert!(mapping.same_mapping(&reconstructed));
+        assert_ne!(
+            mapping,
+            LogarithmicMapping::with_relative_accuracy(1.1e-2).unwrap()
+        );
+        assert_ne!(
+            mapping,
+            LogarithmicMapping::with_gamma_offset(mapping.gamma(), 1.0).unwrap()
+        );
+
+        let cubic = CubicallyInterpolatedMapping::with_relative_accuracy(1e-2).unwrap();
+        assert_eq!(
+            cubic,
+            CubicallyInterpolatedMapping::with_relative_accuracy(1e-2).unwrap()
+        );
+        assert_ne!(
+            cubic,
+            CubicallyInterpolatedMapping::with_relative_accuracy(2e-2).unwrap()
+        );
+        let logarithmic = LogarithmicMapping::with_gamma_offset(cubic.gamma(), 0.0).unwrap();
+        assert!(!cubic.same_mapping(&logarithmic));
+    }
+
+    #[test]
+    fn test_index_range() {
+        let cubic = CubicallyInterpolatedMapping::with_relative_accuracy(2e-2).unwrap();
+        let log = LogarithmicMapping::with_relative_accuracy(2e-2).unwrap();
+        for (min_value, max_value) in [(1.0, 2.0), (0.001, 1e6), (17.5, 17.5)] {
+            let (min_index, max_index) = cubic.index_range(min_value, max_value);
+            assert_eq!(cubic.index(min_value), min_index);
+            assert_eq!(cubic.index(max_value), max_index);
+            assert!(cubic.lower_bound(min_index) <= min_value + EPSILON);
+            assert!(cubic.upper_bound(max_index) >= max_value - EPSILON);
+
+            let (min_index, max_index) = log.index_range(min_value, max_value);
+            assert_eq!(log.index(min_value), min_index);
+            assert_eq!(log.index(max_value), max_index);
+            for value in [min_value, (min_value + max_value) / 2.0, max_value] {
+                assert!(min_index <= log.index(value) && log.index(value) <= max_index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_relative_accuracy() {
+        for relative_accuracy in [1e-3, 1e-2, 5e-2] {
+            let log = LogarithmicMapping::with_relative_accuracy(relative_accuracy).unwrap();
+            let cubic =
+                CubicallyInterpolatedMapping::with_relative_accuracy(relative_accuracy).unwrap();
+            let mut value = 1e-9;
+            while value < 1e12 {
+                assert!(log.check_relative_accuracy(value) <= relative_accuracy + EPSILON);
+                assert!(cubic.check_relative_accuracy(value) <= relative_accuracy + EPSILON);
+                value *= 1.37;
+            }
+        }
+        let mapping = LogarithmicMapping::with_relative_accuracy(1e-2).unwrap();
+        let value = mapping.value(42);
+        assert!(mapping.check_relative_accuracy(value) <= EPSILON);
+        assert!(mapping.check_relative_accuracy(mapping.lower_bound(42) * 1.0001) > 9e-3);
+        assert_eq!(
+            mapping.check_relative_accuracy(5.0),
+            mapping.check_relative_accuracy(-5.0)
+        );
+        assert!(mapping.check_relative_accuracy(0.0).is_nan());
+        assert!(mapping.check_relative_accuracy(f64::INFINITY).is_nan());
+        assert!(mapping.check_relative_accuracy(f64::NAN).is_nan());
+
+        let lower = mapping.with_representative_value(RepresentativeValue::LowerBound);
+        let mut max_error: f64 = 0.0;
+        let mut value = 1e-3;
+        while value < 1e6 {
+            let error = lower.check_relative_accuracy(value);
+            assert!(error <= 2.0 * lower.get_relative_accuracy() + EPSILON);
+            max_error = max_error.max(error);
+            value *= 1.0037;
+        }
+        assert!(max_error > 1.9 * lower.get_relative_accuracy());
+    }
+
+    #[test]
+    fn test_planned_bin_count() {
+        for (relative_accuracy, min_value, max_value) in
+            [(2e-2, 1.0, 1000.0), (1e-2, 0.05, 3.0), (5e-2, 7.0, 7.0)]
+        {
+            let planned = planned_bin_count(relative_accuracy, min_value, max_value).unwrap();
+            let mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy).unwrap();
+            let mut store = UnboundedSizeDenseStore::new();
+            let mut value = min_value;
+            while value < max_value {
+                store.add(mapping.index(value), 1.0);
+                value *= 1.001;
+            }
+            store.add(mapping.index(max_value), 1.0);
+            assert_eq!(planned, store.num_populated_bins());
+        }
+        assert!(planned_bin_count(2e-2, 0.0, 1.0).is_err());
+        assert!(planned_bin_count(2e-2, 2.0, 1.0).is_err());
+        assert!(planned_bin_count(1.0, 1.0, 2.0).is_err());
+    }
 }
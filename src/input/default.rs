@@ -2,23 +2,23 @@ use super::*;
 use crate::error::Error;
 use std::io::ErrorKind;
 
-pub struct DefaultInput {
-    vec: Vec<u8>,
+pub struct DefaultInput<B: AsRef<[u8]> = Vec<u8>> {
+    vec: B,
     pos: usize,
     end: usize,
 }
 
-impl DefaultInput {
-    pub fn wrap(vec: Vec<u8>) -> DefaultInput {
+impl<B: AsRef<[u8]>> DefaultInput<B> {
+    pub fn wrap(vec: B) -> DefaultInput<B> {
         DefaultInput {
             pos: 0,
-            end: vec.len(),
+            end: vec.as_ref().len(),
             vec,
         }
     }
 }
 
-impl Input for DefaultInput {
+impl<B: AsRef<[u8]>> Input for DefaultInput<B> {
     fn has_remaining(&self) -> bool {
         self.pos < self.end
     }
@@ -27,7 +27,7 @@ impl Input for DefaultInput {
         if self.pos >= self.end {
             return Err(Error::IoError(ErrorKind::UnexpectedEof));
         }
-        let value = self.vec[self.pos];
+        let value = self.vec.as_ref()[self.pos];
         self.pos += 1;
         Ok(value)
     }
@@ -37,15 +37,16 @@ impl Input for DefaultInput {
             return Err(Error::IoError(ErrorKind::UnexpectedEof));
         }
 
+        let vec = self.vec.as_ref();
         let value = u64::from_le_bytes([
-            self.vec[self.pos],
-            self.vec[self.pos + 1],
-            self.vec[self.pos + 2],
-            self.vec[self.pos + 3],
-            self.vec[self.pos + 4],
-            self.vec[self.pos + 5],
-            self.vec[self.pos + 6],
-            self.vec[self.pos + 7],
+            vec[self.pos],
+            vec[self.pos + 1],
+            vec[self.pos + 2],
+            vec[self.pos + 3],
+            vec[self.pos + 4],
+            vec[self.pos + 5],
+            vec[self.pos + 6],
+            vec[self.pos + 7],
         ]);
         self.pos += 8;
         Ok(value)
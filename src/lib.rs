@@ -60,6 +60,7 @@ Decode from bytes:
 ```
  */
 
+mod aggregator;
 mod error;
 pub mod index_mapping;
 mod input;
@@ -67,15 +68,29 @@ mod output;
 mod serde;
 mod sketch;
 pub mod store;
+mod uddsketch;
 
+pub use self::aggregator::SketchAggregator;
 pub use self::error::Error;
 pub use self::index_mapping::CubicallyInterpolatedMapping;
 pub use self::index_mapping::IndexMapping;
 pub use self::index_mapping::LogarithmicMapping;
+pub use self::index_mapping::RepresentativeValue;
 use self::input::DefaultInput;
 use self::output::DefaultOutput;
+pub use self::serde::{append_proto, split_protos};
+#[cfg(feature = "rayon")]
+pub use self::sketch::parallel_quantiles;
+pub use self::sketch::ClampPolicy;
 pub use self::sketch::DDSketch;
+pub use self::sketch::DurationUnit;
+pub use self::sketch::FrozenSketch;
+pub use self::sketch::RankMode;
+pub use self::sketch::SketchDigest;
+pub use self::sketch::Timer;
 pub use self::store::CollapsingHighestDenseStore;
 pub use self::store::CollapsingLowestDenseStore;
+pub use self::store::GrowthDirection;
 pub use self::store::Store;
 pub use self::store::UnboundedSizeDenseStore;
+pub use self::uddsketch::UDDSketch;
@@ -8,3 +8,20 @@ pub trait Output {
     fn write_long_le(&mut self, value: i64) -> Result<(), Error>;
     fn write_double_le(&mut self, value: f64) -> Result<(), Error>;
 }
+
+impl Output for Vec<u8> {
+    fn write_byte(&mut self, value: u8) -> Result<(), Error> {
+        self.push(value);
+        Ok(())
+    }
+
+    fn write_long_le(&mut self, value: i64) -> Result<(), Error> {
+        self.extend(i64::to_le_bytes(value));
+        Ok(())
+    }
+
+    fn write_double_le(&mut self, value: f64) -> Result<(), Error> {
+        self.extend(f64::to_le_bytes(value));
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 use crate::error::Error;
+use crate::index_mapping::IndexMapping;
 use crate::input::*;
 use crate::output::Output;
-use crate::sketch::Flag;
+use crate::sketch::{DDSketch, Flag};
+use crate::store::Store;
+use std::io::ErrorKind;
 
 const SIGNIFICAND_WIDTH: i64 = 53;
 const SIGNIFICAND_MASK: i64 = 0x000fffffffffffff;
@@ -165,6 +168,46 @@ pub fn encode_signed_var_long(output: &mut impl Output, value: i64) -> Result<()
     encode_unsigned_var_long(output, zig_zag_encode(value))
 }
 
+/// Appends the encoding of `sketch` to `buf`, prefixed with its length as a 4-byte little-endian
+/// integer, so that several sketches can be stored back to back and read with [`split_protos`].
+pub fn append_proto<I: IndexMapping, S: Store>(
+    buf: &mut Vec<u8>,
+    sketch: &DDSketch<I, S>,
+) -> Result<(), Error> {
+    let start = buf.len();
+    buf.extend([0; 4]);
+    sketch.encode_to(buf)?;
+    let length = match u32::try_from(buf.len() - start - 4) {
+        Ok(length) => length,
+        Err(_) => {
+            buf.truncate(start);
+            return Err(Error::InvalidArgument("Too large encoded sketch."));
+        }
+    };
+    buf[start..start + 4].copy_from_slice(&length.to_le_bytes());
+    Ok(())
+}
+
+/// Splits a buffer written by [`append_proto`] into the encodings of the individual sketches.
+pub fn split_protos(buf: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut protos = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        if pos + 4 > buf.len() {
+            return Err(Error::IoError(ErrorKind::UnexpectedEof));
+        }
+        let length = u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+        let start = pos + 4;
+        let end = start + length as usize;
+        if end > buf.len() {
+            return Err(Error::IoError(ErrorKind::UnexpectedEof));
+        }
+        protos.push(&buf[start..end]);
+        pos = end;
+    }
+    Ok(protos)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
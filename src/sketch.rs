@@ -5,10 +5,16 @@ use crate::index_mapping::{
 use crate::input::Input;
 use crate::output::Output;
 use crate::store::{
-    BinEncodingMode, CollapsingHighestDenseStore, CollapsingLowestDenseStore, Store,
-    UnboundedSizeDenseStore,
+    is_valid_count, BinEncodingMode, CollapsingHighestDenseStore, CollapsingLowestDenseStore,
+    Store, UnboundedSizeDenseStore,
 };
 use crate::{serde, DefaultInput, DefaultOutput};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+/// The version byte `encode_versioned` prefixes the encoding with.
+const SERIALIZATION_VERSION: u8 = 1;
 
 pub struct DDSketch<I: IndexMapping, S: Store> {
     index_mapping: I,
@@ -17,6 +23,175 @@ pub struct DDSketch<I: IndexMapping, S: Store> {
     negative_value_store: S,
     positive_value_store: S,
     zero_count: f64,
+    rank_mode: RankMode,
+    clamp_policy: ClampPolicy,
+    duration_unit: DurationUnit,
+}
+
+/// What to do with values beyond the range the index mapping can represent, including infinities.
+/// `NaN` values are always rejected.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ClampPolicy {
+    #[default]
+    Drop,
+    /// Counts the value in the bin of the largest indexable value of the same sign.
+    ClampToEdge,
+    /// Rejects the value, `accept` and `accept_with_count` drop it while `try_accept` and
+    /// `try_accept_with_count` return an error.
+    Error,
+}
+
+/// How the rank of a quantile is derived from the total count.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum RankMode {
+    /// `q * count`, the value is the first one whose cumulative count reaches the rank.
+    QTimesN,
+    /// `q * (count - 1)`, the value is the first one whose cumulative count exceeds the rank, as
+    /// computed by the Java implementation.
+    #[default]
+    QTimesNMinus1,
+}
+
+impl RankMode {
+    fn rank(&self, quantile: f64, count: f64) -> f64 {
+        match self {
+            RankMode::QTimesN => quantile * count,
+            RankMode::QTimesNMinus1 => quantile * (count - 1.0),
+        }
+    }
+
+    fn reached(&self, n: f64, rank: f64) -> bool {
+        match self {
+            RankMode::QTimesN => n >= rank,
+            RankMode::QTimesNMinus1 => n > rank,
+        }
+    }
+}
+
+/// The unit `DDSketch::add_duration` records durations in.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum DurationUnit {
+    #[default]
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl DurationUnit {
+    fn per_second(&self) -> f64 {
+        match self {
+            DurationUnit::Seconds => 1.0,
+            DurationUnit::Milliseconds => 1e3,
+            DurationUnit::Microseconds => 1e6,
+            DurationUnit::Nanoseconds => 1e9,
+        }
+    }
+
+    pub fn value_of(&self, duration: Duration) -> f64 {
+        match self {
+            DurationUnit::Nanoseconds => duration.as_nanos() as f64,
+            _ => duration.as_secs_f64() * self.per_second(),
+        }
+    }
+
+    /// Converts a value in this unit back to a duration, saturating negative values to zero.
+    pub fn duration_of(&self, value: f64) -> Duration {
+        Duration::try_from_secs_f64(value / self.per_second()).unwrap_or(if value > 0.0 {
+            Duration::MAX
+        } else {
+            Duration::ZERO
+        })
+    }
+}
+
+/// The bin a quantile falls into, by index for the negative and positive value stores.
+pub(crate) enum QuantileBin {
+    Negative(i32),
+    Zero,
+    Positive(i32),
+}
+
+pub(crate) fn ascending_bins(store: &impl Store) -> impl Iterator<Item = (i32, f64)> + '_ {
+    store
+        .iter_range(store.get_min_index(), store.get_max_index())
+        .filter(|bin| bin.1 > 0.0)
+}
+
+pub(crate) fn descending_bins(store: &impl Store) -> impl Iterator<Item = (i32, f64)> + '_ {
+    store
+        .iter_range(store.get_min_index(), store.get_max_index())
+        .descending()
+        .filter(|bin| bin.1 > 0.0)
+}
+
+/// Merges two streams of bins sorted by index in the same direction, adding the counts of bins of
+/// the same index.
+fn merge_bins(
+    a: impl Iterator<Item = (i32, f64)>,
+    b: impl Iterator<Item = (i32, f64)>,
+    descending: bool,
+) -> impl Iterator<Item = (i32, f64)> {
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+    std::iter::from_fn(move || match (a.peek().copied(), b.peek().copied()) {
+        (Some(bin_a), Some(bin_b)) if bin_a.0 == bin_b.0 => {
+            a.next();
+            b.next();
+            Some((bin_a.0, bin_a.1 + bin_b.1))
+        }
+        (Some(bin_a), Some(bin_b)) if (bin_a.0 < bin_b.0) != descending => a.next(),
+        (Some(_), Some(_)) => b.next(),
+        (Some(_), None) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+/// Walks the bins in ascending value order, so the negative ones by descending index, and returns
+/// the bin the rank of `quantile` falls into along with its count.
+pub(crate) fn find_quantile_bin(
+    rank_mode: RankMode,
+    quantile: f64,
+    count: f64,
+    negative_bins: impl Iterator<Item = (i32, f64)>,
+    zero_count: f64,
+    positive_bins: impl Iterator<Item = (i32, f64)>,
+) -> Option<(QuantileBin, f64)> {
+    if !(0.0..=1.0).contains(&quantile) || count <= 0.0 || count.is_nan() {
+        return None;
+    }
+    let rank = rank_mode.rank(quantile, count);
+    let mut n: f64 = 0.0;
+    // Rounding may keep the cumulative count below a rank close to the total, the highest bin is
+    // then the answer.
+    let mut last_bin = None;
+
+    let zero_bin = Some((QuantileBin::Zero, zero_count)).filter(|_| zero_count > 0.0);
+    let bins = negative_bins
+        .map(|(index, bin_count)| (QuantileBin::Negative(index), bin_count))
+        .chain(zero_bin)
+        .chain(positive_bins.map(|(index, bin_count)| (QuantileBin::Positive(index), bin_count)));
+    for (bin, bin_count) in bins {
+        n += bin_count;
+        if rank_mode.reached(n, rank) {
+            return Some((bin, bin_count));
+        }
+        last_bin = Some((bin, bin_count));
+    }
+    last_bin
+}
+
+/// Records the time elapsed since it was created into a sketch when dropped. A forgotten timer
+/// records nothing.
+pub struct Timer<'a, I: IndexMapping, S: Store> {
+    sketch: &'a mut DDSketch<I, S>,
+    start: Instant,
+}
+
+impl<I: IndexMapping, S: Store> Drop for Timer<'_, I, S> {
+    fn drop(&mut self) {
+        self.sketch.add_duration(self.start.elapsed());
+    }
 }
 
 #[derive(PartialEq)]
@@ -32,28 +207,347 @@ pub enum FlagType {
 }
 
 impl<I: IndexMapping, S: Store> DDSketch<I, S> {
+    fn new(index_mapping: I, negative_value_store: S, positive_value_store: S) -> DDSketch<I, S> {
+        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
+        let max_indexed_value = index_mapping.max_indexable_value();
+        DDSketch {
+            index_mapping,
+            negative_value_store,
+            positive_value_store,
+            min_indexed_value,
+            max_indexed_value,
+            zero_count: 0.0,
+            rank_mode: RankMode::default(),
+            clamp_policy: ClampPolicy::default(),
+            duration_unit: DurationUnit::default(),
+        }
+    }
+
+    pub fn clone_empty(&self) -> DDSketch<I, S>
+    where
+        I: Clone,
+        S: Clone,
+    {
+        let mut negative_value_store = self.negative_value_store.clone();
+        negative_value_store.clear();
+        negative_value_store.compact();
+        let mut positive_value_store = self.positive_value_store.clone();
+        positive_value_store.clear();
+        positive_value_store.compact();
+        let mut sketch = DDSketch::new(
+            self.index_mapping.clone(),
+            negative_value_store,
+            positive_value_store,
+        );
+        sketch.rank_mode = self.rank_mode;
+        sketch.clamp_policy = self.clamp_policy;
+        sketch.duration_unit = self.duration_unit;
+        sketch.min_indexed_value = self.min_indexed_value;
+        sketch
+    }
+
+    /// The relative accuracy guaranteed by the index mapping.
+    pub fn get_relative_accuracy(&self) -> f64 {
+        self.index_mapping.get_relative_accuracy()
+    }
+
+    pub fn get_rank_mode(&self) -> RankMode {
+        self.rank_mode
+    }
+
+    pub fn set_rank_mode(&mut self, rank_mode: RankMode) {
+        self.rank_mode = rank_mode;
+    }
+
+    pub fn get_clamp_policy(&self) -> ClampPolicy {
+        self.clamp_policy
+    }
+
+    pub fn set_clamp_policy(&mut self, clamp_policy: ClampPolicy) {
+        self.clamp_policy = clamp_policy;
+    }
+
+    /// Values whose magnitude is at most the zero threshold are counted as zero.
+    pub fn get_zero_threshold(&self) -> f64 {
+        self.min_indexed_value
+    }
+
+    /// Sets the zero threshold, which cannot be below the smallest value the index mapping can
+    /// index. Raising it moves the counts of the bins it covers to the zero count, lowering it is
+    /// only possible while the zero count is empty.
+    pub fn set_zero_threshold(&mut self, zero_threshold: f64) -> Result<(), Error> {
+        if !(zero_threshold >= f64::max(0.0, self.index_mapping.min_indexable_value())
+            && zero_threshold < self.max_indexed_value)
+        {
+            return Err(Error::InvalidArgument(
+                "The zero threshold is out of the range of the index mapping.",
+            ));
+        }
+        if zero_threshold < self.min_indexed_value && self.zero_count > 0.0 {
+            return Err(Error::InvalidArgument(
+                "The zero threshold cannot be lowered once values were counted as zero.",
+            ));
+        }
+        self.min_indexed_value = zero_threshold;
+        self.move_small_bins_to_zero();
+        Ok(())
+    }
+
+    /// Moves the counts of the bins whose values are all within the zero threshold to the zero
+    /// count.
+    fn move_small_bins_to_zero(&mut self) {
+        if self.min_indexed_value <= self.index_mapping.min_indexable_value() {
+            return;
+        }
+        let mut max_index = self.index_mapping.index(self.min_indexed_value);
+        if self.index_mapping.upper_bound(max_index) > self.min_indexed_value {
+            max_index -= 1;
+        }
+        for store in [
+            &mut self.negative_value_store,
+            &mut self.positive_value_store,
+        ] {
+            if store.is_empty() || store.get_min_index() > max_index {
+                continue;
+            }
+            let min_index = store.get_min_index();
+            let count: f64 = store
+                .iter_range(min_index, max_index)
+                .map(|bin| bin.1)
+                .sum();
+            store.clear_range(min_index, max_index);
+            self.zero_count += count;
+        }
+    }
+
+    pub fn get_duration_unit(&self) -> DurationUnit {
+        self.duration_unit
+    }
+
+    pub fn set_duration_unit(&mut self, duration_unit: DurationUnit) {
+        self.duration_unit = duration_unit;
+    }
+
+    /// Adds `duration` as a value in the duration unit of the sketch.
+    pub fn add_duration(&mut self, duration: Duration) {
+        self.accept(self.duration_unit.value_of(duration));
+    }
+
+    /// Starts a timer that adds the time elapsed until it is dropped with `add_duration`.
+    pub fn time(&mut self) -> Timer<'_, I, S> {
+        Timer {
+            sketch: self,
+            start: Instant::now(),
+        }
+    }
+
     pub fn accept(&mut self, value: f64) {
         self.accept_with_count(value, 1.0);
     }
 
     pub fn accept_with_count(&mut self, value: f64, count: f64) {
-        if count < 0.0 {
-            return;
-        }
+        let _ = self.try_accept_with_count(value, count);
+    }
 
-        if value < -self.max_indexed_value || value > self.max_indexed_value {
-            return;
+    pub fn try_accept(&mut self, value: f64) -> Result<(), Error> {
+        self.try_accept_with_count(value, 1.0)
+    }
+
+    /// Adds `value` with `count`. `NaN` values are rejected with an error, infinities are out of the
+    /// indexable range and follow the [`ClampPolicy`]. Counts that are not positive and finite are
+    /// ignored, as the stores do, so that the total and the quantiles remain well-defined.
+    pub fn try_accept_with_count(&mut self, value: f64, count: f64) -> Result<(), Error> {
+        if !is_valid_count(count) {
+            return Ok(());
         }
 
+        let value = match self.clamp(value)? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+
         if value > self.min_indexed_value {
             self.positive_value_store
-                .add(self.index_mapping.index(value), 1.0);
+                .add(self.index_mapping.index(value), count);
         } else if value < -self.min_indexed_value {
             self.negative_value_store
-                .add(self.index_mapping.index(-value), 1.0);
+                .add(self.index_mapping.index(-value), count);
+        } else {
+            self.zero_count += count;
+        }
+        Ok(())
+    }
+
+    /// Adds every value of `values` with a count of 1, like [`DDSketch::accept`]. Values are mapped
+    /// in chunks and each store is extended once per chunk to cover all of its indexes.
+    pub fn insert_many(&mut self, values: &[f64]) {
+        const CHUNK_SIZE: usize = 1024;
+        let mut negative_indexes = Vec::with_capacity(CHUNK_SIZE);
+        let mut positive_indexes = Vec::with_capacity(CHUNK_SIZE);
+        for chunk in values.chunks(CHUNK_SIZE) {
+            negative_indexes.clear();
+            positive_indexes.clear();
+            for &value in chunk {
+                let value = match self.clamp(value) {
+                    Ok(Some(value)) => value,
+                    _ => continue,
+                };
+                if value > self.min_indexed_value {
+                    positive_indexes.push(self.index_mapping.index(value));
+                } else if value < -self.min_indexed_value {
+                    negative_indexes.push(self.index_mapping.index(-value));
+                } else {
+                    self.zero_count += 1.0;
+                }
+            }
+            DDSketch::<I, S>::add_indexes(&mut self.negative_value_store, &negative_indexes);
+            DDSketch::<I, S>::add_indexes(&mut self.positive_value_store, &positive_indexes);
+        }
+    }
+
+    /// Adds each of `values` with the count at the same position of `weights`, like
+    /// [`DDSketch::accept_with_count`]. Nothing is added unless both slices have the same length
+    /// and every weight is positive and finite.
+    pub fn add_weighted_values(&mut self, values: &[f64], weights: &[f64]) -> Result<(), Error> {
+        if values.len() != weights.len() {
+            return Err(Error::InvalidArgument(
+                "The values and the weights must have the same length.",
+            ));
+        }
+        if !weights
+            .iter()
+            .all(|&weight| weight > 0.0 && weight.is_finite())
+        {
+            return Err(Error::InvalidArgument(
+                "The weights must be positive and finite.",
+            ));
+        }
+        for (&value, &weight) in values.iter().zip(weights) {
+            self.accept_with_count(value, weight);
+        }
+        Ok(())
+    }
+
+    /// Adds `n` samples of a log-normal distribution with parameters `mu` and `sigma`, the same
+    /// samples for the same `seed`, as a reproducible workload for benchmarks and accuracy tests.
+    #[cfg(feature = "bench")]
+    pub fn fill_log_normal(&mut self, n: usize, mu: f64, sigma: f64, seed: u64) {
+        let mut state = seed;
+        let mut next_uniform = || {
+            // SplitMix64, mapped to (0, 1] so that the logarithm below stays finite.
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            ((z >> 11) + 1) as f64 / (1u64 << 53) as f64
+        };
+        for _ in 0..n {
+            // Box-Muller transform of two uniform samples into a standard normal one.
+            let normal = (-2.0 * next_uniform().ln()).sqrt()
+                * (2.0 * std::f64::consts::PI * next_uniform()).cos();
+            self.accept((mu + sigma * normal).exp());
+        }
+    }
+
+    fn add_indexes(store: &mut S, indexes: &[i32]) {
+        let (min_index, max_index) = match (indexes.iter().min(), indexes.iter().max()) {
+            (Some(&min_index), Some(&max_index)) => (min_index, max_index),
+            _ => return,
+        };
+        if min_index == max_index {
+            store.add(min_index, indexes.len() as f64);
+            return;
+        }
+        // Adding both extremes first extends the range once, the other indexes then fall within it.
+        store.add_bins_sorted(&[(min_index, 1.0), (max_index, 1.0)]);
+        let (mut skip_min, mut skip_max) = (true, true);
+        for &index in indexes {
+            if skip_min && index == min_index {
+                skip_min = false;
+            } else if skip_max && index == max_index {
+                skip_max = false;
+            } else {
+                store.add(index, 1.0);
+            }
+        }
+    }
+
+    /// Applies the clamp policy, `None` means the value is dropped.
+    fn clamp(&self, value: f64) -> Result<Option<f64>, Error> {
+        if value.is_nan() {
+            return Err(Error::InvalidArgument("NaN values cannot be added."));
+        }
+        if value < -self.max_indexed_value || value > self.max_indexed_value {
+            match self.clamp_policy {
+                ClampPolicy::Drop => return Ok(None),
+                ClampPolicy::ClampToEdge => {
+                    return Ok(Some(
+                        value.clamp(-self.max_indexed_value, self.max_indexed_value),
+                    ))
+                }
+                ClampPolicy::Error => {
+                    return Err(Error::InvalidArgument(
+                        "The value is out of the indexable range.",
+                    ))
+                }
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// Returns the count of the bin `value` maps to.
+    pub fn count_near(&self, value: f64) -> f64 {
+        let value = match self.clamp(value) {
+            Ok(Some(value)) => value,
+            _ => return 0.0,
+        };
+        let (store, index) = if value > self.min_indexed_value {
+            (&self.positive_value_store, self.index_mapping.index(value))
+        } else if value < -self.min_indexed_value {
+            (&self.negative_value_store, self.index_mapping.index(-value))
         } else {
-            self.zero_count += 1.0;
+            return self.zero_count;
+        };
+        if store.is_empty() || index < store.get_min_index() || index > store.get_max_index() {
+            return 0.0;
         }
+        store.get_count(index - store.get_offset())
+    }
+
+    /// Iterates the populated bins in ascending value order as `(lower, upper, count)`, where the
+    /// bounds come from the index mapping, so bins of consecutive indexes share a boundary. The zero
+    /// bin spans the values too small to be indexed.
+    pub fn iter_bins(&mut self) -> impl Iterator<Item = (f64, f64, f64)> + '_ {
+        let index_mapping = &self.index_mapping;
+        let negative_bins = self
+            .negative_value_store
+            .get_descending_iter()
+            .filter(|bin| bin.1 != 0.0)
+            .map(move |(index, count)| {
+                (
+                    -index_mapping.upper_bound(index),
+                    -index_mapping.lower_bound(index),
+                    count,
+                )
+            });
+        let zero_bin = (self.zero_count > 0.0).then_some((
+            -self.min_indexed_value,
+            self.min_indexed_value,
+            self.zero_count,
+        ));
+        let positive_bins = self
+            .positive_value_store
+            .get_ascending_iter()
+            .filter(|bin| bin.1 != 0.0)
+            .map(move |(index, count)| {
+                (
+                    index_mapping.lower_bound(index),
+                    index_mapping.upper_bound(index),
+                    count,
+                )
+            });
+        negative_bins.chain(zero_bin).chain(positive_bins)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -76,7 +570,7 @@ impl<I: IndexMapping, S: Store> DDSketch<I, S> {
 
     pub fn get_sum(&mut self) -> Option<f64> {
         let count = self.get_count();
-        if count <= 0.0 {
+        if count <= 0.0 || count.is_nan() {
             return None;
         }
 
@@ -94,90 +588,341 @@ impl<I: IndexMapping, S: Store> DDSketch<I, S> {
     }
 
     pub fn get_max(&mut self) -> Option<f64> {
-        if !self.positive_value_store.is_empty() {
-            Some(
-                self.index_mapping
-                    .value(self.positive_value_store.get_max_index()),
-            )
+        // Bins may span a range while holding no count, such a sketch is empty.
+        if self.get_count() <= 0.0 {
+            return None;
+        }
+        if let Some(index) = self.positive_value_store.last_populated_index() {
+            Some(self.index_mapping.value(index))
         } else if self.zero_count > 0.0 {
             Some(0.0)
-        } else if !self.negative_value_store.is_empty() {
-            Some(
-                -self
-                    .index_mapping
-                    .value(self.negative_value_store.get_min_index()),
-            )
         } else {
-            None
+            self.negative_value_store
+                .first_populated_index()
+                .map(|index| -self.index_mapping.value(index))
         }
     }
 
     pub fn get_min(&mut self) -> Option<f64> {
-        if !self.negative_value_store.is_empty() {
-            Some(
-                -self
-                    .index_mapping
-                    .value(self.negative_value_store.get_max_index()),
-            )
+        // Bins may span a range while holding no count, such a sketch is empty.
+        if self.get_count() <= 0.0 {
+            return None;
+        }
+        if let Some(index) = self.negative_value_store.last_populated_index() {
+            Some(-self.index_mapping.value(index))
         } else if self.zero_count > 0.0 {
             Some(0.0)
-        } else if !self.positive_value_store.is_empty() {
-            Some(
-                self.index_mapping
-                    .value(self.positive_value_store.get_min_index()),
-            )
         } else {
-            None
+            self.positive_value_store
+                .first_populated_index()
+                .map(|index| self.index_mapping.value(index))
         }
     }
 
     pub fn get_average(&mut self) -> Option<f64> {
         let count = self.get_count();
-        if count <= 0.0 {
+        if count <= 0.0 || count.is_nan() {
             return None;
         }
         Some(self.get_sum()? / count)
     }
 
     pub fn get_value_at_quantile(self: &mut DDSketch<I, S>, quantile: f64) -> Option<f64> {
-        if !(0.0..=1.0).contains(&quantile) {
+        self.get_value_and_count_at_quantile(quantile)
+            .map(|(value, _)| value)
+    }
+
+    /// Returns the value at `quantile` along with the count of the bin it falls into.
+    pub fn get_value_and_count_at_quantile(&mut self, quantile: f64) -> Option<(f64, f64)> {
+        let (bin, count) = self.quantile_bin(quantile)?;
+        let value = match bin {
+            QuantileBin::Negative(index) => -self.index_mapping.value(index),
+            QuantileBin::Zero => 0.0,
+            QuantileBin::Positive(index) => self.index_mapping.value(index),
+        };
+        Some((value, count))
+    }
+
+    fn quantile_bin(&mut self, quantile: f64) -> Option<(QuantileBin, f64)> {
+        let count = self.get_count();
+        find_quantile_bin(
+            self.rank_mode,
+            quantile,
+            count,
+            descending_bins(&self.negative_value_store),
+            self.zero_count,
+            ascending_bins(&self.positive_value_store),
+        )
+    }
+
+    /// Returns the relative error guaranteed for the value at `quantile`. This is the relative
+    /// accuracy of the mapping, unless the quantile falls into a bin that other bins were collapsed
+    /// into, whose value stands for every value down or up to the edge of the indexable range.
+    pub fn effective_accuracy_at_quantile(&self, quantile: f64) -> f64 {
+        let relative_accuracy = self.index_mapping.get_relative_accuracy();
+        let count = self.zero_count
+            + self.negative_value_store.total_count_cached()
+            + self.positive_value_store.total_count_cached();
+        let bin = find_quantile_bin(
+            self.rank_mode,
+            quantile,
+            count,
+            descending_bins(&self.negative_value_store),
+            self.zero_count,
+            ascending_bins(&self.positive_value_store),
+        );
+        let (store, index) = match bin {
+            Some((QuantileBin::Negative(index), _)) => (&self.negative_value_store, index),
+            Some((QuantileBin::Positive(index), _)) => (&self.positive_value_store, index),
+            _ => return relative_accuracy,
+        };
+        let (from_index, to_index) = match store.collapsed_range() {
+            Some((from_index, to_index)) if from_index <= index && index <= to_index => {
+                (from_index, to_index)
+            }
+            _ => return relative_accuracy,
+        };
+        let value = self.index_mapping.value(index);
+        let lowest_value = f64::max(
+            self.index_mapping.lower_bound(from_index),
+            self.min_indexed_value,
+        );
+        let highest_value = f64::min(
+            self.index_mapping.upper_bound(to_index),
+            self.max_indexed_value,
+        );
+        f64::max(
+            relative_accuracy,
+            f64::max(
+                (value - lowest_value) / lowest_value,
+                (highest_value - value) / highest_value,
+            ),
+        )
+    }
+
+    /// Computes the value at `quantile` of the merge of `a` and `b` by walking the bins of both in
+    /// order, without building the merged sketch. The rank mode of `a` applies.
+    pub fn quantile_of_union(
+        a: &DDSketch<I, S>,
+        b: &DDSketch<I, impl Store>,
+        quantile: f64,
+    ) -> Option<f64> {
+        if !a.index_mapping.same_mapping(&b.index_mapping) {
             return None;
         }
+        let zero_count = a.zero_count + b.zero_count;
+        let count = zero_count
+            + a.negative_value_store.total_count_cached()
+            + a.positive_value_store.total_count_cached()
+            + b.negative_value_store.total_count_cached()
+            + b.positive_value_store.total_count_cached();
+        let negative_bins = merge_bins(
+            descending_bins(&a.negative_value_store),
+            descending_bins(&b.negative_value_store),
+            true,
+        );
+        let positive_bins = merge_bins(
+            ascending_bins(&a.positive_value_store),
+            ascending_bins(&b.positive_value_store),
+            false,
+        );
+        let (bin, _) = find_quantile_bin(
+            a.rank_mode,
+            quantile,
+            count,
+            negative_bins,
+            zero_count,
+            positive_bins,
+        )?;
+        Some(match bin {
+            QuantileBin::Negative(index) => -a.index_mapping.value(index),
+            QuantileBin::Zero => 0.0,
+            QuantileBin::Positive(index) => a.index_mapping.value(index),
+        })
+    }
+
+    /// Returns `(lower, estimate, upper)` where the bounds are the estimate of `quantile` scaled by
+    /// one minus and one plus the relative accuracy, ordered so that `lower <= upper`.
+    pub fn quantile_band(&mut self, quantile: f64) -> Option<(f64, f64, f64)> {
+        let estimate = self.get_value_at_quantile(quantile)?;
+        let relative_accuracy = self.index_mapping.get_relative_accuracy();
+        let a = estimate * (1.0 - relative_accuracy);
+        let b = estimate * (1.0 + relative_accuracy);
+        Some((a.min(b), estimate, a.max(b)))
+    }
 
+    /// Returns the fraction of values below `value`, counting half of the bin `value` falls into,
+    /// so that the rank of `0.0` covers all negative values and half of the zero count.
+    pub fn get_rank(&mut self, value: f64) -> Option<f64> {
         let count = self.get_count();
-        if count <= 0.0 {
+        if count <= 0.0 || count.is_nan() {
             return None;
         }
 
-        let rank = quantile * (count - 1.0);
-
         let mut n: f64 = 0.0;
+        if value > self.min_indexed_value {
+            let index = self.index_mapping.index(value);
+            n += self.negative_value_store.get_total_count() + self.zero_count;
+            for bin in self.positive_value_store.get_ascending_iter() {
+                if bin.0 < index {
+                    n += bin.1;
+                } else {
+                    if bin.0 == index {
+                        n += bin.1 / 2.0;
+                    }
+                    break;
+                }
+            }
+        } else if value < -self.min_indexed_value {
+            let index = self.index_mapping.index(-value);
+            for bin in self.negative_value_store.get_descending_iter() {
+                if bin.0 > index {
+                    n += bin.1;
+                } else {
+                    if bin.0 == index {
+                        n += bin.1 / 2.0;
+                    }
+                    break;
+                }
+            }
+        } else {
+            n += self.negative_value_store.get_total_count() + self.zero_count / 2.0;
+        }
+        Some(n / count)
+    }
 
-        let negative_bin_iterator = self.negative_value_store.get_descending_iter();
-        for bin in negative_bin_iterator {
-            n += bin.1;
-            if n > rank {
-                return Some(-self.index_mapping.value(bin.0));
+    /// Compares the values at each of `quantiles`, which must be within a relative tolerance `tol`.
+    pub fn approx_eq(
+        &mut self,
+        other: &mut DDSketch<impl IndexMapping, impl Store>,
+        quantiles: &[f64],
+        tol: f64,
+    ) -> bool {
+        for &quantile in quantiles {
+            match (
+                self.get_value_at_quantile(quantile),
+                other.get_value_at_quantile(quantile),
+            ) {
+                (Some(v1), Some(v2)) => {
+                    if f64::abs(v1 - v2) > tol * f64::max(v1.abs(), v2.abs()) {
+                        return false;
+                    }
+                }
+                (None, None) => {}
+                _ => return false,
             }
         }
+        true
+    }
 
-        n += self.zero_count;
-        if n > rank {
-            return Some(0.0);
+    /// Whether the value at each of `quantiles` is at least the value of `other` at that quantile.
+    /// Quantiles either sketch cannot answer make it false.
+    pub fn stochastically_dominates(
+        &mut self,
+        other: &mut DDSketch<impl IndexMapping, impl Store>,
+        quantiles: &[f64],
+    ) -> bool {
+        quantiles.iter().all(|&quantile| {
+            match (
+                self.get_value_at_quantile(quantile),
+                other.get_value_at_quantile(quantile),
+            ) {
+                (Some(value), Some(other_value)) => value >= other_value,
+                _ => false,
+            }
+        })
+    }
+
+    /// The earth mover's distance between the distributions of both sketches, each normalized to a
+    /// total count of one, with the counts of each bin at the value of the bin. The mappings may
+    /// differ. Returns `NaN` if either sketch is empty.
+    pub fn earth_movers_distance(
+        &mut self,
+        other: &mut DDSketch<impl IndexMapping, impl Store>,
+    ) -> f64 {
+        let masses = self.normalized_masses();
+        let other_masses = other.normalized_masses();
+        if masses.is_empty() || other_masses.is_empty() {
+            return f64::NAN;
+        }
+        let (mut i, mut j) = (0, 0);
+        let (mut cdf, mut other_cdf): (f64, f64) = (0.0, 0.0);
+        let mut distance = 0.0;
+        let mut previous_value: Option<f64> = None;
+        while i < masses.len() || j < other_masses.len() {
+            let value = match (masses.get(i), other_masses.get(j)) {
+                (Some(mass), Some(other_mass)) => f64::min(mass.0, other_mass.0),
+                (Some(mass), None) => mass.0,
+                (None, Some(other_mass)) => other_mass.0,
+                (None, None) => break,
+            };
+            if let Some(previous_value) = previous_value {
+                distance += (cdf - other_cdf).abs() * (value - previous_value);
+            }
+            if i < masses.len() && masses[i].0 == value {
+                cdf += masses[i].1;
+                i += 1;
+            }
+            if j < other_masses.len() && other_masses[j].0 == value {
+                other_cdf += other_masses[j].1;
+                j += 1;
+            }
+            previous_value = Some(value);
         }
+        distance
+    }
 
-        let positive_bin_iterator = self.positive_value_store.get_ascending_iter();
-        for bin in positive_bin_iterator {
-            n += bin.1;
-            if n > rank {
-                return Some(self.index_mapping.value(bin.0));
+    /// The value of every non-empty bin, in ascending order, with its share of the total count.
+    fn normalized_masses(&mut self) -> Vec<(f64, f64)> {
+        let count = self.get_count();
+        if count <= 0.0 || count.is_nan() {
+            return Vec::new();
+        }
+        let mut masses = Vec::new();
+        for (index, bin_count) in self.negative_value_store.get_descending_iter() {
+            if bin_count > 0.0 {
+                masses.push((-self.index_mapping.value(index), bin_count / count));
+            }
+        }
+        if self.zero_count > 0.0 {
+            masses.push((0.0, self.zero_count / count));
+        }
+        for (index, bin_count) in self.positive_value_store.get_ascending_iter() {
+            if bin_count > 0.0 {
+                masses.push((self.index_mapping.value(index), bin_count / count));
             }
         }
+        masses
+    }
 
-        None
+    /// Returns the relative error of the value at each of `quantiles` against `reference`, or `NaN`
+    /// where either sketch cannot answer the quantile.
+    pub fn quantile_errors(
+        &mut self,
+        reference: &mut DDSketch<impl IndexMapping, impl Store>,
+        quantiles: &[f64],
+    ) -> Vec<f64> {
+        quantiles
+            .iter()
+            .map(|&quantile| {
+                match (
+                    self.get_value_at_quantile(quantile),
+                    reference.get_value_at_quantile(quantile),
+                ) {
+                    (Some(value), Some(expected)) if value == expected => 0.0,
+                    (Some(value), Some(expected)) => f64::abs(value - expected) / expected.abs(),
+                    _ => f64::NAN,
+                }
+            })
+            .collect()
     }
 
     pub fn decode_and_merge_with(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        self.decode_slice_and_merge_with(&bytes)
+    }
+
+    /// Like [`DDSketch::decode_and_merge_with`], without taking ownership of the bytes.
+    pub(crate) fn decode_slice_and_merge_with(&mut self, bytes: &[u8]) -> Result<(), Error> {
         let mut input = DefaultInput::wrap(bytes);
         while input.has_remaining() {
             let flag = Flag::decode(&mut input)?;
@@ -204,14 +949,14 @@ impl<I: IndexMapping, S: Store> DDSketch<I, S> {
                                     gamma,
                                     index_offset,
                                 )?;
-                            if self.index_mapping.to_string() != decoded_index_mapping.to_string() {
+                            if !self.index_mapping.same_mapping(&decoded_index_mapping) {
                                 return Err(Error::InvalidArgument("Unmatched IndexMapping"));
                             }
                         }
                         IndexMappingLayout::LOG => {
                             let decoded_index_mapping =
                                 LogarithmicMapping::with_gamma_offset(gamma, index_offset)?;
-                            if self.index_mapping.to_string() != decoded_index_mapping.to_string() {
+                            if !self.index_mapping.same_mapping(&decoded_index_mapping) {
                                 return Err(Error::InvalidArgument("Unmatched IndexMapping"));
                             }
                         }
@@ -232,8 +977,45 @@ impl<I: IndexMapping, S: Store> DDSketch<I, S> {
         Ok(())
     }
 
+    /// Decodes each of `blobs` into an empty copy of this sketch and merges the copies, reducing
+    /// them in parallel with the `rayon` feature. Up to the rounding of fractional counts, the
+    /// result does not depend on the order of `blobs` or the parallelism. Nothing is merged if any
+    /// blob fails to decode.
+    pub fn merge_serialized_batch(&mut self, blobs: &[Vec<u8>]) -> Result<(), Error>
+    where
+        I: Clone + Send + Sync,
+        S: Clone + Send + Sync,
+    {
+        let decode = |blob: &Vec<u8>| -> Result<DDSketch<I, S>, Error> {
+            let mut partial = self.clone_empty();
+            partial.decode_slice_and_merge_with(blob)?;
+            Ok(partial)
+        };
+        let merge = |mut merged: DDSketch<I, S>, partial| -> Result<DDSketch<I, S>, Error> {
+            merged.merge_owned(partial)?;
+            Ok(merged)
+        };
+        #[cfg(feature = "rayon")]
+        let merged = blobs
+            .par_iter()
+            .map(decode)
+            .try_reduce(|| self.clone_empty(), merge)?;
+        #[cfg(not(feature = "rayon"))]
+        let merged = blobs
+            .iter()
+            .map(decode)
+            .try_fold(self.clone_empty(), |merged, partial| {
+                merge(merged, partial?)
+            })?;
+        self.merge_owned(merged)
+    }
+
+    /// Merges `other` into this sketch, which must have the same index mapping. The bins of each
+    /// store are added in one pass after a single range extension. When the stores of `other` keep
+    /// their counts as `f64` arrays, as the dense stores of the same configuration do, the arrays
+    /// are added to the counts of the same indexes without going through bins.
     pub fn merge_with(&mut self, other: &mut DDSketch<I, impl Store>) -> Result<(), Error> {
-        if self.index_mapping.to_string() != other.index_mapping.to_string() {
+        if !self.index_mapping.same_mapping(&other.index_mapping) {
             return Err(Error::InvalidArgument("Unmatched indexMapping."));
         }
         self.negative_value_store
@@ -241,24 +1023,343 @@ impl<I: IndexMapping, S: Store> DDSketch<I, S> {
         self.positive_value_store
             .merge_with(&mut other.positive_value_store);
         self.zero_count += other.zero_count;
+        self.adopt_zero_threshold(other.min_indexed_value);
+        Ok(())
+    }
+
+    /// Merges keep the coarser of both zero thresholds, so the bins of either sketch below it are
+    /// counted as zero.
+    fn adopt_zero_threshold(&mut self, other_zero_threshold: f64) {
+        self.min_indexed_value = f64::max(self.min_indexed_value, other_zero_threshold);
+        self.move_small_bins_to_zero();
+    }
+
+    pub fn merge_weighted(
+        &mut self,
+        other: &mut DDSketch<I, impl Store>,
+        weight: f64,
+    ) -> Result<(), Error> {
+        if !(weight > 0.0 && weight.is_finite()) {
+            return Err(Error::InvalidArgument(
+                "The weight must be positive and finite.",
+            ));
+        }
+        if !self.index_mapping.same_mapping(&other.index_mapping) {
+            return Err(Error::InvalidArgument("Unmatched indexMapping."));
+        }
+        for bin in other.negative_value_store.get_descending_iter() {
+            self.negative_value_store.add_bin((bin.0, bin.1 * weight));
+        }
+        for bin in other.positive_value_store.get_descending_iter() {
+            self.positive_value_store.add_bin((bin.0, bin.1 * weight));
+        }
+        self.zero_count += other.zero_count * weight;
+        self.adopt_zero_threshold(other.min_indexed_value);
+        Ok(())
+    }
+
+    /// Merges a sketch of the same type, adding the bins of the smaller store into the larger one
+    /// when both stores have the same bin limit, so that the limits of `self` are kept.
+    pub fn merge_owned(&mut self, other: DDSketch<I, S>) -> Result<(), Error> {
+        if !self.index_mapping.same_mapping(&other.index_mapping) {
+            return Err(Error::InvalidArgument("Unmatched indexMapping."));
+        }
+        for (store, mut other_store) in [
+            (&mut self.negative_value_store, other.negative_value_store),
+            (&mut self.positive_value_store, other.positive_value_store),
+        ] {
+            if store.num_populated_bins() < other_store.num_populated_bins()
+                && store.max_num_bins() == other_store.max_num_bins()
+            {
+                store.swap(&mut other_store);
+            }
+            store.merge_with(&mut other_store);
+        }
+        self.zero_count += other.zero_count;
+        self.adopt_zero_threshold(other.min_indexed_value);
         Ok(())
     }
 
+    /// Consumes both sketches and returns their merge, built on the larger store of each sign as in
+    /// [`DDSketch::merge_owned`].
+    pub fn into_merged(self, other: DDSketch<I, S>) -> Result<DDSketch<I, S>, Error> {
+        let mut merged = self;
+        merged.merge_owned(other)?;
+        Ok(merged)
+    }
+
     pub fn encode(&self) -> Result<Vec<u8>, Error> {
         let mut output = DefaultOutput::with_capacity(64);
-        self.index_mapping.encode(&mut output)?;
+        self.encode_to(&mut output)?;
+        Ok(output.trimmed_copy())
+    }
+
+    /// Encodes the sketch like `encode`, prefixed with a version byte so that the format can
+    /// evolve. The unprefixed encoding stays compatible with the other DDSketch implementations.
+    pub fn encode_versioned(&self) -> Result<Vec<u8>, Error> {
+        let mut output = DefaultOutput::with_capacity(64);
+        output.write_byte(SERIALIZATION_VERSION)?;
+        self.encode_to(&mut output)?;
+        Ok(output.trimmed_copy())
+    }
+
+    /// Decodes the output of `encode_versioned` and merges it into this sketch, rejecting
+    /// versions this crate does not know with `Error::UnsupportedVersion`.
+    pub fn decode_versioned_and_merge_with(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        match bytes.split_first() {
+            Some((&SERIALIZATION_VERSION, encoded)) => self.decode_slice_and_merge_with(encoded),
+            Some((&version, _)) => Err(Error::UnsupportedVersion(version)),
+            None => Err(Error::InvalidArgument("Missing the serialization version.")),
+        }
+    }
+
+    pub(crate) fn encode_to(&self, output: &mut impl Output) -> Result<(), Error> {
+        // Same layout as sketches-java: mapping, zero count, positive store, negative store.
+        self.index_mapping.encode(output)?;
 
         if self.zero_count != 0.0 {
-            Flag::ZERO_COUNT.encode(&mut output)?;
-            serde::encode_var_double(&mut output, self.zero_count)?;
+            Flag::ZERO_COUNT.encode(output)?;
+            serde::encode_var_double(output, self.zero_count)?;
         }
 
         self.positive_value_store
-            .encode(&mut output, FlagType::PositiveStore)?;
+            .encode(output, FlagType::PositiveStore)?;
         self.negative_value_store
-            .encode(&mut output, FlagType::NegativeStore)?;
+            .encode(output, FlagType::NegativeStore)?;
+        Ok(())
+    }
 
-        Ok(output.trimmed_copy())
+    /// Hashes the mapping parameters and the populated bins with FNV-1a, so the result does not
+    /// depend on the store type or its internal layout.
+    pub fn content_hash(&mut self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        hash = fnv_1a(hash, &[self.index_mapping.layout() as u8]);
+        hash = fnv_1a(hash, &self.index_mapping.gamma().to_le_bytes());
+        hash = fnv_1a(hash, &self.index_mapping.index_offset().to_le_bytes());
+        hash = fnv_1a(hash, &self.zero_count.to_le_bytes());
+        for (flag_type, store) in [
+            (FlagType::NegativeStore, &mut self.negative_value_store),
+            (FlagType::PositiveStore, &mut self.positive_value_store),
+        ] {
+            hash = fnv_1a(hash, &[flag_type as u8]);
+            for bin in store.get_ascending_iter() {
+                hash = fnv_1a(hash, &bin.0.to_le_bytes());
+                hash = fnv_1a(hash, &bin.1.to_le_bytes());
+            }
+        }
+        hash
+    }
+
+    /// Summarizes the sketch with its count, min, max, sum and the values at `quantiles`.
+    pub fn digest(&mut self, quantiles: &[f64]) -> SketchDigest {
+        SketchDigest {
+            count: self.get_count(),
+            min: self.get_min().unwrap_or(f64::NAN),
+            max: self.get_max().unwrap_or(f64::NAN),
+            sum: self.get_sum().unwrap_or(f64::NAN),
+            quantiles: quantiles
+                .iter()
+                .map(|&quantile| {
+                    let value = self.get_value_at_quantile(quantile);
+                    (quantile, value.unwrap_or(f64::NAN))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn freeze(&mut self) -> FrozenSketch {
+        let mut values = Vec::new();
+        let mut cumulative_counts = Vec::new();
+        let mut n: f64 = 0.0;
+
+        for bin in self.negative_value_store.get_descending_iter() {
+            n += bin.1;
+            values.push(-self.index_mapping.value(bin.0));
+            cumulative_counts.push(n);
+        }
+
+        if self.zero_count > 0.0 {
+            n += self.zero_count;
+            values.push(0.0);
+            cumulative_counts.push(n);
+        }
+
+        for bin in self.positive_value_store.get_ascending_iter() {
+            n += bin.1;
+            values.push(self.index_mapping.value(bin.0));
+            cumulative_counts.push(n);
+        }
+
+        FrozenSketch {
+            values,
+            cumulative_counts,
+            count: self.get_count(),
+            rank_mode: self.rank_mode,
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv_1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// A read-only view of a sketch answering quantile queries by binary search over cumulative counts.
+pub struct FrozenSketch {
+    values: Vec<f64>,
+    cumulative_counts: Vec<f64>,
+    count: f64,
+    rank_mode: RankMode,
+}
+
+impl FrozenSketch {
+    pub fn get_count(&self) -> f64 {
+        self.count
+    }
+
+    pub fn get_value_at_quantile(&self, quantile: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&quantile) {
+            return None;
+        }
+
+        if self.count <= 0.0 || self.count.is_nan() {
+            return None;
+        }
+
+        let rank = self.rank_mode.rank(quantile, self.count);
+        let position = self
+            .cumulative_counts
+            .partition_point(|&n| !self.rank_mode.reached(n, rank));
+        self.values
+            .get(position.min(self.values.len().saturating_sub(1)))
+            .copied()
+    }
+}
+
+/// Computes the value at `quantile` of each of `sketches` in parallel.
+#[cfg(feature = "rayon")]
+pub fn parallel_quantiles<I, S>(sketches: &mut [DDSketch<I, S>], quantile: f64) -> Vec<Option<f64>>
+where
+    I: IndexMapping + Send,
+    S: Store + Send,
+{
+    sketches
+        .par_iter_mut()
+        .map(|sketch| sketch.get_value_at_quantile(quantile))
+        .collect()
+}
+
+impl<I: IndexMapping, S: Store> From<&DDSketch<I, S>> for Vec<u8> {
+    fn from(sketch: &DDSketch<I, S>) -> Vec<u8> {
+        let mut output = Vec::new();
+        sketch
+            .encode_to(&mut output)
+            .expect("writing to a Vec never fails");
+        output
+    }
+}
+
+/// Decodes a sketch whose encoding starts with its index mapping, as the encodings of this crate
+/// and of the Java implementation do.
+impl<I: IndexMapping> TryFrom<&[u8]> for DDSketch<I, UnboundedSizeDenseStore> {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        let mut input = DefaultInput::wrap(&bytes[..bytes.len().min(17)]);
+        let flag = Flag::decode(&mut input)?;
+        if !matches!(flag.get_type()?, FlagType::IndexMapping) {
+            return Err(Error::InvalidArgument("Missing IndexMapping"));
+        }
+        let gamma = input.read_double_le()?;
+        let index_offset = input.read_double_le()?;
+        // The layout is checked against the one of `I` while decoding.
+        let index_mapping = I::with_gamma_offset(gamma, index_offset)?;
+        let mut sketch = DDSketch::new(
+            index_mapping,
+            UnboundedSizeDenseStore::new(),
+            UnboundedSizeDenseStore::new(),
+        );
+        sketch.decode_slice_and_merge_with(bytes)?;
+        Ok(sketch)
+    }
+}
+
+/// A summary of a sketch holding its count, min, max, sum and a few quantiles. Missing values are
+/// kept as `NaN` and returned as `None`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SketchDigest {
+    count: f64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    quantiles: Vec<(f64, f64)>,
+}
+
+impl SketchDigest {
+    pub fn get_count(&self) -> f64 {
+        self.count
+    }
+
+    pub fn get_min(&self) -> Option<f64> {
+        Some(self.min).filter(|min| !min.is_nan())
+    }
+
+    pub fn get_max(&self) -> Option<f64> {
+        Some(self.max).filter(|max| !max.is_nan())
+    }
+
+    pub fn get_sum(&self) -> Option<f64> {
+        Some(self.sum).filter(|sum| !sum.is_nan())
+    }
+
+    /// Returns the value of `quantile` if it was one of the quantiles of the digest.
+    pub fn get_value_at_quantile(&self, quantile: f64) -> Option<f64> {
+        self.quantiles
+            .iter()
+            .find(|(q, _)| *q == quantile)
+            .map(|(_, value)| *value)
+            .filter(|value| !value.is_nan())
+    }
+
+    /// Encodes the digest as four doubles followed by the number of quantiles and their
+    /// `(quantile, value)` pairs.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::with_capacity(34 + 16 * self.quantiles.len());
+        for value in [self.count, self.min, self.max, self.sum] {
+            output.write_double_le(value)?;
+        }
+        serde::encode_unsigned_var_long(&mut output, self.quantiles.len() as i64)?;
+        for &(quantile, value) in &self.quantiles {
+            output.write_double_le(quantile)?;
+            output.write_double_le(value)?;
+        }
+        Ok(output)
+    }
+
+    pub fn decode(bytes: Vec<u8>) -> Result<SketchDigest, Error> {
+        let mut input = DefaultInput::wrap(bytes);
+        let count = input.read_double_le()?;
+        let min = input.read_double_le()?;
+        let max = input.read_double_le()?;
+        let sum = input.read_double_le()?;
+        let num_quantiles = serde::decode_unsigned_var_long(&mut input)?;
+        let mut quantiles = Vec::new();
+        for _ in 0..num_quantiles {
+            quantiles.push((input.read_double_le()?, input.read_double_le()?));
+        }
+        Ok(SketchDigest {
+            count,
+            min,
+            max,
+            sum,
+            quantiles,
+        })
     }
 }
 
@@ -271,17 +1372,11 @@ impl DDSketch<CubicallyInterpolatedMapping, CollapsingLowestDenseStore> {
             CubicallyInterpolatedMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = CollapsingLowestDenseStore::with_capacity(max_num_bins)?;
         let positive_value_store = CollapsingLowestDenseStore::with_capacity(max_num_bins)?;
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
     }
 }
 
@@ -294,17 +1389,11 @@ impl DDSketch<CubicallyInterpolatedMapping, CollapsingHighestDenseStore> {
             CubicallyInterpolatedMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = CollapsingHighestDenseStore::with_capacity(max_num_bins)?;
         let positive_value_store = CollapsingHighestDenseStore::with_capacity(max_num_bins)?;
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
     }
 }
 
@@ -316,17 +1405,48 @@ impl DDSketch<CubicallyInterpolatedMapping, UnboundedSizeDenseStore> {
             CubicallyInterpolatedMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = UnboundedSizeDenseStore::new();
         let positive_value_store = UnboundedSizeDenseStore::new();
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
+    }
+
+    /// Builds a sketch from fixed histogram buckets given as `(upper_bound, count)` pairs sorted by
+    /// upper bound. Each bucket spans from the previous upper bound, or from zero for a first bucket
+    /// with a positive upper bound, and its whole count is added at the middle of that span. An
+    /// infinite last bucket is counted at its lower bound. The relative accuracy therefore only
+    /// holds up to the width of the buckets.
+    pub fn from_histogram_buckets(
+        buckets: &[(f64, f64)],
+        relative_accuracy: f64,
+    ) -> Result<DDSketch<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>, Error> {
+        let mut sketch = DDSketch::unbounded_dense(relative_accuracy)?;
+        let mut lower_bound = match buckets.first() {
+            Some(&(upper_bound, _)) if upper_bound > 0.0 => 0.0,
+            Some(&(upper_bound, _)) => upper_bound,
+            None => return Ok(sketch),
+        };
+        for &(upper_bound, count) in buckets {
+            if upper_bound.is_nan() || upper_bound < lower_bound {
+                return Err(Error::InvalidArgument(
+                    "The bucket upper bounds must be sorted.",
+                ));
+            }
+            if !(count >= 0.0 && count.is_finite()) {
+                return Err(Error::InvalidArgument(
+                    "The bucket counts must be finite and not negative.",
+                ));
+            }
+            let value = if upper_bound == f64::INFINITY {
+                lower_bound
+            } else {
+                lower_bound + (upper_bound - lower_bound) / 2.0
+            };
+            sketch.try_accept_with_count(value, count)?;
+            lower_bound = upper_bound;
+        }
+        Ok(sketch)
     }
 }
 
@@ -338,17 +1458,11 @@ impl DDSketch<LogarithmicMapping, CollapsingLowestDenseStore> {
         let index_mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = CollapsingLowestDenseStore::with_capacity(max_num_bins)?;
         let positive_value_store = CollapsingLowestDenseStore::with_capacity(max_num_bins)?;
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
     }
 }
 
@@ -360,17 +1474,11 @@ impl DDSketch<LogarithmicMapping, CollapsingHighestDenseStore> {
         let index_mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = CollapsingHighestDenseStore::with_capacity(max_num_bins)?;
         let positive_value_store = CollapsingHighestDenseStore::with_capacity(max_num_bins)?;
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
     }
 }
 
@@ -381,17 +1489,11 @@ impl DDSketch<LogarithmicMapping, UnboundedSizeDenseStore> {
         let index_mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy)?;
         let negative_value_store = UnboundedSizeDenseStore::new();
         let positive_value_store = UnboundedSizeDenseStore::new();
-        let min_indexed_value = f64::max(0.0, index_mapping.min_indexable_value());
-        let max_indexed_value = index_mapping.max_indexable_value();
-        let zero_count = 0.0;
-        Ok(DDSketch {
+        Ok(DDSketch::new(
             index_mapping,
             negative_value_store,
             positive_value_store,
-            min_indexed_value,
-            max_indexed_value,
-            zero_count,
-        })
+        ))
     }
 }
 
@@ -440,3 +1542,111 @@ impl FlagType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::GrowthDirection;
+
+    #[test]
+    fn test_zero_total_count() {
+        let index_mapping = LogarithmicMapping::with_relative_accuracy(1e-2).unwrap();
+        let mut sketch = DDSketch::new(
+            index_mapping,
+            UnboundedSizeDenseStore::<f32>::default(),
+            UnboundedSizeDenseStore::<f32>::default(),
+        );
+        // Counts too small for f32 extend the range of the stores but round to zero.
+        sketch.accept_with_count(3.0, 1e-50);
+        sketch.accept_with_count(-7.0, 1e-50);
+        assert!(!sketch.positive_value_store.is_empty());
+        assert_eq!(0.0, sketch.get_count());
+
+        assert_eq!(None, sketch.get_value_at_quantile(0.5));
+        assert_eq!(None, sketch.get_value_and_count_at_quantile(1.0));
+        assert_eq!(None, sketch.quantile_band(0.0));
+        assert_eq!(None, sketch.get_rank(3.0));
+        assert_eq!(None, sketch.get_max());
+        assert_eq!(None, sketch.get_min());
+        assert_eq!(None, sketch.get_average());
+        assert_eq!(None, sketch.freeze().get_value_at_quantile(0.5));
+    }
+
+    #[test]
+    fn test_into_merged() {
+        let build = |from: i32, to: i32| {
+            let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+            for i in from..to {
+                sketch.accept(i as f64);
+                sketch.accept(-(i as f64));
+            }
+            sketch
+        };
+        let small = build(1, 3);
+        let large = build(1, 5000);
+        let mut expected = build(1, 3);
+        expected.merge_with(&mut build(1, 5000)).unwrap();
+
+        // The larger stores are kept, so their counts are not moved or reallocated.
+        let offsets = (
+            large.negative_value_store.get_offset(),
+            large.positive_value_store.get_offset(),
+        );
+        let mut merged = small.into_merged(large).unwrap();
+        assert_eq!(
+            offsets,
+            (
+                merged.negative_value_store.get_offset(),
+                merged.positive_value_store.get_offset()
+            )
+        );
+        assert_eq!(expected.encode().unwrap(), merged.encode().unwrap());
+        assert_eq!(expected.get_count(), merged.get_count());
+
+        let other = DDSketch::unbounded_dense(2e-2).unwrap();
+        assert!(build(1, 3).into_merged(other).is_err());
+    }
+
+    #[test]
+    fn test_merge_owned_keeps_store_settings() {
+        let build = |from: i32, to: i32, precision_warning: bool| {
+            let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+            sketch.positive_value_store =
+                UnboundedSizeDenseStore::new().with_growth_direction(if precision_warning {
+                    GrowthDirection::TowardHigh
+                } else {
+                    GrowthDirection::TowardLow
+                });
+            sketch
+                .positive_value_store
+                .set_precision_warning(precision_warning);
+            for i in from..to {
+                sketch.accept(i as f64);
+            }
+            sketch
+        };
+        // A lossy add is only reported by stores with the precision warning enabled.
+        let lossy_adds = |sketch: &mut DDSketch<_, UnboundedSizeDenseStore>| {
+            sketch.positive_value_store.add(-1000, 1e16);
+            sketch.positive_value_store.add(-1000, 1.0);
+            sketch.positive_value_store.precision_warnings()
+        };
+
+        let mut small = build(1, 3, true);
+        small.merge_owned(build(1, 5000, false)).unwrap();
+        assert_eq!(
+            GrowthDirection::TowardHigh,
+            small.positive_value_store.growth_direction()
+        );
+        assert_eq!(1, lossy_adds(&mut small));
+
+        let mut small = build(1, 3, false);
+        small.merge_owned(build(1, 5000, true)).unwrap();
+        assert_eq!(5001.0, small.get_count());
+        assert_eq!(
+            GrowthDirection::TowardLow,
+            small.positive_value_store.growth_direction()
+        );
+        assert_eq!(0, lossy_adds(&mut small));
+    }
+}
@@ -3,13 +3,8 @@ use crate::serde;
 
 #[derive(Clone)]
 pub struct CollapsingHighestDenseStore {
-    counts: Vec<f64>,
-    offset: i32,
-    min_index: i32,
-    max_index: i32,
+    dense: DenseCounts,
     is_collapsed: bool,
-    array_length_overhead: i32,
-    array_length_growth_increment: i32,
     max_num_bins: i32,
 }
 
@@ -24,259 +19,272 @@ impl CollapsingHighestDenseStore {
         Ok(CollapsingHighestDenseStore {
             max_num_bins,
             is_collapsed: false,
-            counts: Vec::new(),
-            offset: 0,
-            min_index: i32::MAX,
-            max_index: i32::MIN,
-            array_length_growth_increment: 64,
-            array_length_overhead: 6,
+            dense: DenseCounts::default(),
         })
     }
 
-    fn normalize(&mut self, index: i32) -> i32 {
-        if index > self.max_index {
+    fn get_new_length(&self, new_min_index: i32, new_max_index: i32) -> i32 {
+        self.dense
+            .grown_length(new_min_index, new_max_index)
+            .map_or(self.max_num_bins, |length| length.min(self.max_num_bins))
+    }
+
+    fn center_counts(&mut self, new_min_index: i32, new_max_index: i32) {
+        let middle_index = new_min_index + (new_max_index - new_min_index + 1) / 2;
+        let shift = self.dense.offset + self.dense.length() / 2 - middle_index;
+        self.dense.shift_counts(shift);
+        self.dense.min_index = new_min_index;
+        self.dense.max_index = new_max_index;
+    }
+}
+
+impl DenseStore for CollapsingHighestDenseStore {
+    fn dense(&self) -> &DenseCounts<Self::CountType> {
+        &self.dense
+    }
+
+    fn dense_mut(&mut self) -> &mut DenseCounts<Self::CountType> {
+        &mut self.dense
+    }
+
+    fn normalize(&mut self, index: i32) -> Option<usize> {
+        // Indexes within [min_index, max_index] only cost the bounds checks below.
+        let index = clamp_index(index);
+        if index > self.dense.max_index {
             if self.is_collapsed {
-                return self.get_length() - 1;
+                return serde::i32_to_usize_exact(self.dense.max_index - self.dense.offset).ok();
             } else {
-                self.extend_range(index, index);
+                dense::extend_range(self, index, index);
                 if self.is_collapsed {
-                    return self.get_length() - 1;
+                    return serde::i32_to_usize_exact(self.dense.max_index - self.dense.offset)
+                        .ok();
                 }
             }
-        } else if index < self.min_index {
-            self.extend_range(index, index);
+        } else if index < self.dense.min_index {
+            dense::extend_range(self, index, index);
         }
-        index - self.offset
-    }
-
-    fn get_length(&self) -> i32 {
-        self.counts.len() as i32
+        serde::i32_to_usize_exact(index - self.dense.offset).ok()
     }
 
     fn extend_range(&mut self, new_min_index: i32, new_max_index: i32) {
-        let new_min_index = new_min_index.min(self.min_index);
-        let new_max_index = new_max_index.max(self.max_index);
+        let new_min_index = new_min_index.min(self.dense.min_index);
+        let new_max_index = new_max_index.max(self.dense.max_index);
 
         if self.is_empty() {
             let initial_length = self.get_new_length(new_min_index, new_max_index);
-            if initial_length >= self.get_length() {
-                self.counts.resize(initial_length as usize, 0.0);
+            if initial_length >= self.dense.length() {
+                self.dense.counts.resize(initial_length as usize, 0.0);
             }
-            self.offset = new_min_index;
-            self.min_index = new_min_index;
-            self.max_index = new_max_index;
-            self.adjust(new_min_index, new_max_index);
-        } else if new_min_index >= self.offset && new_max_index < self.offset + self.get_length() {
-            self.min_index = new_min_index;
-            self.max_index = new_max_index;
+            // Only the lowest indexes fit in the bin budget, higher ones are collapsed.
+            let new_max_index =
+                if new_max_index as i64 - new_min_index as i64 + 1 > self.dense.length() as i64 {
+                    self.is_collapsed = true;
+                    new_min_index + self.dense.length() - 1
+                } else {
+                    new_max_index
+                };
+            self.dense.offset = new_min_index;
+            self.dense.min_index = new_min_index;
+            self.dense.max_index = new_max_index;
+            dense::adjust(self, new_min_index, new_max_index);
+        } else if new_min_index >= self.dense.offset
+            && new_max_index < self.dense.offset + self.dense.length()
+        {
+            self.dense.min_index = new_min_index;
+            self.dense.max_index = new_max_index;
         } else {
             // To avoid shifting too often when nearing the capacity of the array, we may grow it before
             // we actually reach the capacity.
             let new_length = self.get_new_length(new_min_index, new_max_index);
-            if new_length > self.get_length() {
-                self.counts.resize(new_length as usize, 0.0);
+            if new_length > self.dense.length() {
+                self.dense.counts.resize(new_length as usize, 0.0);
             }
-            self.adjust(new_min_index, new_max_index);
+            dense::adjust(self, new_min_index, new_max_index);
         }
     }
 
     fn adjust(&mut self, new_min_index: i32, new_max_index: i32) {
-        if new_max_index - new_min_index + 1 > self.get_length() {
+        if new_max_index - new_min_index + 1 > self.dense.length() {
             // The range of indices is too wide, buckets of lowest indices need to be collapsed.
 
-            let new_max_index = new_min_index + self.get_length() - 1;
+            let new_max_index = new_min_index + self.dense.length() - 1;
 
-            if new_max_index <= self.min_index {
+            if new_max_index <= self.dense.min_index {
                 // There will be only one non-empty bucket.
 
                 let total_count = self.get_total_count();
-                self.reset_counts(self.min_index, self.max_index);
-                self.offset = new_min_index;
-                self.max_index = new_max_index;
-                let move_index = (self.get_length() - 1) as usize;
-                self.counts[move_index] = total_count;
+                self.dense
+                    .reset_counts(self.dense.min_index, self.dense.max_index);
+                self.dense.offset = new_min_index;
+                self.dense.max_index = new_max_index;
+                let move_index = (self.dense.length() - 1) as usize;
+                self.dense.counts[move_index] = total_count;
             } else {
-                let shift = self.offset - new_min_index;
+                let shift = self.dense.offset - new_min_index;
 
                 if shift > 0 {
                     // Collapse the buckets.
-                    let collapsed_count =
-                        self.get_total_count_with_range(new_max_index + 1, self.max_index);
-                    self.reset_counts(new_max_index + 1, self.max_index);
-                    self.counts[(new_max_index - self.offset) as usize] += collapsed_count;
-                    self.max_index = new_max_index;
+                    let collapsed_count = self
+                        .dense
+                        .sum_counts(new_max_index + 1, self.dense.max_index);
+                    self.dense
+                        .reset_counts(new_max_index + 1, self.dense.max_index);
+                    self.dense.counts[(new_max_index - self.dense.offset) as usize] +=
+                        collapsed_count;
+                    self.dense.max_index = new_max_index;
                     // Shift the buckets to make room for new_max_index.
-                    self.shift_counts(shift);
+                    self.dense.shift_counts(shift);
                 } else {
                     // Shift the buckets to make room for new_min_index.
-                    self.shift_counts(shift);
-                    self.max_index = new_max_index;
+                    self.dense.shift_counts(shift);
+                    self.dense.max_index = new_max_index;
                 }
             }
 
-            self.min_index = new_min_index;
+            self.dense.min_index = new_min_index;
 
             self.is_collapsed = true;
         } else {
             self.center_counts(new_min_index, new_max_index);
         }
     }
+}
 
-    fn get_new_length(&self, new_min_index: i32, new_max_index: i32) -> i32 {
-        let desired_length = (new_max_index as i64 - new_min_index as i64) as i32 + 1;
-        i32::min(
-            self.max_num_bins,
-            ((desired_length + self.array_length_overhead - 1)
-                / self.array_length_growth_increment
-                + 1)
-                * self.array_length_growth_increment,
-        )
-    }
+impl Store for CollapsingHighestDenseStore {
+    type CountType = f64;
 
-    fn center_counts(&mut self, new_min_index: i32, new_max_index: i32) {
-        let middle_index = new_min_index + (new_max_index - new_min_index + 1) / 2;
-        let shift = self.offset + self.get_length() / 2 - middle_index;
-        self.shift_counts(shift);
-        self.min_index = new_min_index;
-        self.max_index = new_max_index;
-    }
-
-    fn shift_counts(&mut self, shift: i32) {
-        let min_array_index = self.min_index - self.offset;
-        let max_array_index = self.max_index - self.offset;
-
-        // System.arraycopy(counts, min_array_index, counts, min_array_index + shift, max_array_index - min_array_index + 1);
-        self.array_copy(
-            min_array_index,
-            min_array_index + shift,
-            max_array_index - min_array_index + 1,
-        );
-
-        if shift > 0 {
-            // Arrays.fill(counts, min_array_index, min_array_index + shift, 0);
-            let from = min_array_index;
-            let to = min_array_index + shift;
-            for index in from..to {
-                self.counts[index as usize] = 0.0;
-            }
-        } else {
-            // Arrays.fill(counts, max_array_index + 1 + shift, max_array_index + 1, 0);
-            let from = max_array_index + 1 + shift;
-            let to = max_array_index + 1;
-            for index in from..to {
-                self.counts[index as usize] = 0.0;
-            }
-        }
+    fn add(&mut self, index: i32, count: f64) {
+        dense::add(self, index, count);
+    }
 
-        self.offset -= shift;
+    fn add_bin(&mut self, bin: (i32, f64)) {
+        dense::add(self, bin.0, bin.1);
     }
 
-    fn array_copy(&mut self, src_pos: i32, dest_pos: i32, length: i32) {
-        if src_pos < dest_pos {
-            let mut offset = length - 1;
-            while offset >= 0 {
-                self.counts[(dest_pos + offset) as usize] =
-                    self.counts[(src_pos + offset) as usize];
-                offset -= 1;
-            }
-        } else if src_pos > dest_pos {
-            let mut offset = 0;
-            while offset < length {
-                self.counts[(dest_pos + offset) as usize] =
-                    self.counts[(src_pos + offset) as usize];
-                offset += 1;
-            }
-        }
+    fn add_bins_sorted(&mut self, bins: &[(i32, f64)]) {
+        dense::add_bins_sorted(self, bins);
     }
 
-    fn get_total_count_with_range(&mut self, from_index: i32, to_index: i32) -> f64 {
-        if self.is_empty() {
-            return 0.0;
-        }
+    fn set_count(&mut self, index: i32, count: f64) {
+        dense::set_count(self, index, count);
+    }
 
-        let from_array_index = i32::max(from_index - self.offset, 0);
-        let to_array_index = i32::min(to_index - self.offset, self.get_length() - 1) + 1;
+    fn merge_max(&mut self, other: &Self) {
+        dense::merge_max(self, other);
+    }
 
-        let mut total_count: f64 = 0.0;
-        for array_index in from_array_index..to_array_index {
-            total_count += self.counts[array_index as usize];
-        }
-        total_count
+    fn counts_slice(&self) -> Option<&[f64]> {
+        dense::counts_slice(self)
     }
 
-    fn reset_counts(&mut self, from_index: i32, to_index: i32) {
-        // Arrays.fill(counts, from_index - self.offset, to_index - self.offset + 1, 0);
-        for index in (from_index - self.offset)..(to_index - self.offset + 1) {
-            self.counts[index as usize] = 0.0;
-        }
+    fn merge_counts(&mut self, min_index: i32, counts: &[f64]) {
+        dense::merge_counts(self, min_index, counts);
     }
-}
 
-impl Store for CollapsingHighestDenseStore {
-    fn add(&mut self, index: i32, count: f64) {
-        if count <= 0.0 {
-            return;
-        }
+    fn append_store(&mut self, other: &Self) {
+        dense::append_store(self, other);
+    }
 
-        let array_index = serde::i32_to_usize_exact(self.normalize(index));
-        match array_index {
-            Ok(index) => {
-                self.counts[index] += count;
-            }
-            _ => {}
-        }
+    fn copy_from(&mut self, src: &Self) {
+        self.dense.copy_from(&src.dense);
+        self.is_collapsed = src.is_collapsed;
+        self.max_num_bins = src.max_num_bins;
     }
 
-    fn add_bin(&mut self, bin: (i32, f64)) {
-        if bin.1 == 0.0 {
-            return;
-        }
-        let array_index = serde::i32_to_usize_exact(self.normalize(bin.0));
-        match array_index {
-            Ok(index) => {
-                self.counts[index] += bin.1;
-            }
-            _ => {}
-        }
+    fn swap(&mut self, other: &mut Self) {
+        self.dense.swap_counts(&mut other.dense);
+        std::mem::swap(&mut self.is_collapsed, &mut other.is_collapsed);
     }
 
     fn clear(&mut self) {
-        self.counts.fill(0.0);
-        self.max_index = i32::MIN;
-        self.min_index = i32::MAX;
-        self.offset = 0;
+        self.dense.clear();
         self.is_collapsed = false;
     }
 
+    fn clear_range(&mut self, from_index: i32, to_index: i32) {
+        dense::clear_range(self, from_index, to_index);
+    }
+
+    fn compact(&mut self) {
+        dense::compact(self);
+    }
+
+    fn resize_to_range(&mut self, min_index: i32, max_index: i32) {
+        self.dense.prefix_sums.invalidate();
+        if min_index > max_index {
+            return;
+        }
+        let (min_index, max_index) = (clamp_index(min_index), clamp_index(max_index));
+        // Only the lowest indexes fit in the bin budget, the counts of higher ones are collapsed
+        // into the highest bin.
+        let budget_max_index = i64::min(
+            max_index as i64,
+            min_index as i64 + self.max_num_bins as i64 - 1,
+        ) as i32;
+        let collapsed_count = self.dense.sum_counts(budget_max_index + 1, max_index);
+        let new_length = self.get_new_length(min_index, budget_max_index);
+        self.dense
+            .reallocate(min_index, budget_max_index, new_length);
+        if collapsed_count > 0.0 {
+            dense::add(self, budget_max_index, collapsed_count);
+            self.is_collapsed = true;
+        }
+    }
+
     fn is_empty(&self) -> bool {
-        self.max_index < self.min_index
+        self.dense.is_empty()
     }
 
     fn get_total_count(&mut self) -> f64 {
-        self.get_total_count_with_range(self.min_index, self.max_index)
+        self.dense
+            .sum_counts(self.dense.min_index, self.dense.max_index)
+    }
+
+    fn total_count_cached(&self) -> f64 {
+        self.dense.total_count
+    }
+
+    fn total_count_recomputed(&mut self) -> f64 {
+        self.dense.total_count_recomputed()
     }
 
     fn get_offset(&self) -> i32 {
-        self.offset
+        self.dense.offset
     }
 
     fn get_min_index(&self) -> i32 {
-        self.min_index
+        self.dense.min_index
     }
 
     fn get_max_index(&self) -> i32 {
-        self.max_index
+        self.dense.max_index
+    }
+
+    fn max_num_bins(&self) -> Option<usize> {
+        Some(self.max_num_bins as usize)
+    }
+
+    fn collapsed_range(&self) -> Option<(i32, i32)> {
+        if self.is_collapsed && !self.is_empty() {
+            Some((self.dense.max_index, MAX_INDEX))
+        } else {
+            None
+        }
     }
 
     fn get_count(&self, i: i32) -> f64 {
-        self.counts[i as usize]
+        self.dense.counts[i as usize]
+    }
+
+    fn approximate_quantile_index_fast(&mut self, quantile: f64) -> Option<i32> {
+        dense::approximate_quantile_index_fast(self, quantile)
     }
 
     fn get_descending_stream(&mut self) -> Vec<(i32, f64)> {
         let mut bins = Vec::new();
-        let mut index = self.max_index;
-        while index >= self.min_index {
-            let value = self.counts[(index - self.offset) as usize];
+        let mut index = self.dense.max_index;
+        while index >= self.dense.min_index {
+            let value = self.dense.counts[(index - self.dense.offset) as usize];
             if value > 0.0 {
                 let bin = (index, value);
                 bins.push(bin);
@@ -288,35 +296,63 @@ impl Store for CollapsingHighestDenseStore {
 
     fn get_ascending_stream(&mut self) -> Vec<(i32, f64)> {
         let mut bins = Vec::new();
-        let mut index = self.min_index;
-        while index <= self.max_index {
-            let value = self.counts[(index - self.offset) as usize];
+        let mut index = self.dense.min_index;
+        while index <= self.dense.max_index {
+            let value = self.dense.counts[(index - self.dense.offset) as usize];
             if value > 0.0 {
                 let bin = (index, value);
                 bins.push(bin);
             }
-            index -= 1;
+            index += 1;
         }
         bins
     }
 
-    fn get_descending_iter(&mut self) -> StoreIter {
+    fn get_descending_iter(&mut self) -> StoreIter<'_> {
         StoreIter::new(
-            self.min_index,
-            self.max_index,
-            self.offset,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
             true,
-            self.counts.as_slice(),
+            self.dense.counts.as_slice(),
         )
     }
 
-    fn get_ascending_iter(&mut self) -> StoreIter {
+    fn get_ascending_iter(&mut self) -> StoreIter<'_> {
         StoreIter::new(
-            self.min_index,
-            self.max_index,
-            self.offset,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
             false,
-            self.counts.as_slice(),
+            self.dense.counts.as_slice(),
+        )
+    }
+
+    fn iter_range(&self, from_index: i32, to_index: i32) -> StoreIter<'_> {
+        StoreIter::in_range(
+            from_index,
+            to_index,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
+            self.dense.counts.as_slice(),
+        )
+    }
+
+    fn set_precision_warning(&mut self, enabled: bool) {
+        self.dense.precision_warning.set_enabled(enabled);
+    }
+
+    fn precision_warnings(&self) -> usize {
+        self.dense.precision_warning.lossy_adds
+    }
+
+    fn self_check(&self) -> Vec<String> {
+        check_dense_counts(
+            &self.dense.counts,
+            self.dense.offset,
+            self.dense.min_index,
+            self.dense.max_index,
         )
     }
 
@@ -328,16 +364,16 @@ impl Store for CollapsingHighestDenseStore {
             return;
         }
 
-        for i in self.min_index..self.max_index {
-            let value = self.counts[(i - self.offset) as usize];
+        for i in self.dense.min_index..self.dense.max_index {
+            let value = self.dense.counts[(i - self.dense.offset) as usize];
             if value != 0.0 {
                 acceptor(i, value);
             }
         }
 
-        let last_count = self.counts[(self.max_index - self.offset) as usize];
+        let last_count = self.dense.counts[(self.dense.max_index - self.dense.offset) as usize];
         if last_count != 0.0 {
-            acceptor(self.max_index, last_count);
+            acceptor(self.dense.max_index, last_count);
         }
     }
 }
@@ -0,0 +1,473 @@
+use super::*;
+
+/// Implemented by the dense stores, which share the handling of their counts and only differ in
+/// how they make room for an index outside of their range.
+pub(super) trait DenseStore: Store {
+    fn dense(&self) -> &DenseCounts<Self::CountType>;
+    fn dense_mut(&mut self) -> &mut DenseCounts<Self::CountType>;
+    /// The array index of the bin `index` falls into, extending the range to hold it, or `None`
+    /// if the store cannot hold it.
+    fn normalize(&mut self, index: i32) -> Option<usize>;
+    /// Whether the range can be extended to hold `[min_index, max_index]`. Collapsing stores hold
+    /// any range, the bins beyond their budget being collapsed.
+    fn can_hold(&self, _min_index: i32, _max_index: i32) -> bool {
+        true
+    }
+    /// Extends the range to hold `[new_min_index, new_max_index]`. Only called through
+    /// [`extend_range`].
+    fn extend_range(&mut self, new_min_index: i32, new_max_index: i32);
+    /// Moves the counts so that the array holds `[new_min_index, new_max_index]`, collapsing the
+    /// bins that do not fit. Only called through [`adjust`].
+    fn adjust(&mut self, new_min_index: i32, new_max_index: i32);
+}
+
+pub(super) fn extend_range<S: DenseStore>(store: &mut S, new_min_index: i32, new_max_index: i32) {
+    store.extend_range(new_min_index, new_max_index);
+    #[cfg(test)]
+    {
+        store.dense_mut().num_extensions += 1;
+    }
+}
+
+/// Adjusts the range of the counts, checking in debug builds that moving and collapsing them kept
+/// their total.
+pub(super) fn adjust<S: DenseStore>(store: &mut S, new_min_index: i32, new_max_index: i32) {
+    #[cfg(debug_assertions)]
+    let total_count = store.get_total_count();
+    store.adjust(new_min_index, new_max_index);
+    #[cfg(debug_assertions)]
+    debug_assert!(
+        counts_match(total_count, store.get_total_count()),
+        "adjusting the range changed the total count from {} to {}",
+        total_count,
+        store.get_total_count()
+    );
+}
+
+pub(super) fn add<S: DenseStore>(store: &mut S, index: i32, count: f64) {
+    store.dense_mut().prefix_sums.invalidate();
+    let count = S::CountType::round_count(count);
+    if !is_valid_count(count) {
+        return;
+    }
+    if let Some(array_index) = store.normalize(index) {
+        store.dense_mut().add_to_bin(array_index, count);
+    }
+}
+
+pub(super) fn set_count<S: DenseStore>(store: &mut S, index: i32, count: f64) {
+    store.dense_mut().prefix_sums.invalidate();
+    let count = S::CountType::round_count(count);
+    if !count.is_finite() || count < 0.0 {
+        return;
+    }
+    let dense = store.dense();
+    if count == 0.0 && (index < dense.min_index || index > dense.max_index) {
+        return;
+    }
+    if let Some(array_index) = store.normalize(index) {
+        let dense = store.dense_mut();
+        dense.total_count += count - dense.counts[array_index].to_f64();
+        dense.counts[array_index] = S::CountType::default().add_count(count);
+    }
+}
+
+pub(super) fn approximate_quantile_index_fast<S: DenseStore>(
+    store: &mut S,
+    quantile: f64,
+) -> Option<i32> {
+    if !store.dense().prefix_sums.is_built() {
+        let cumulative_counts = store.cumulative_counts();
+        store.dense_mut().prefix_sums.build(cumulative_counts);
+    }
+    store.dense().prefix_sums.quantile_index(quantile)
+}
+
+pub(super) fn clear_range<S: DenseStore>(store: &mut S, from_index: i32, to_index: i32) {
+    let dense = store.dense_mut();
+    let from_index = from_index.max(dense.min_index);
+    let to_index = to_index.min(dense.max_index);
+    if from_index > to_index {
+        return;
+    }
+    dense.prefix_sums.invalidate();
+    dense.reset_counts(from_index, to_index);
+    match populated_range(store) {
+        Some((min_index, max_index)) => {
+            let dense = store.dense_mut();
+            dense.min_index = min_index;
+            dense.max_index = max_index;
+            dense.total_count_recomputed();
+        }
+        None => store.clear(),
+    }
+}
+
+/// Reallocates the counts to fit the populated range, or frees them if the store is empty.
+pub(super) fn compact<S: DenseStore>(store: &mut S) {
+    if store.is_empty() {
+        let dense = store.dense_mut();
+        dense.counts = Vec::new();
+        dense.offset = 0;
+        return;
+    }
+    store.resize_to_range(store.get_min_index(), store.get_max_index());
+}
+
+/// Adds bins sorted by index, extending the range once for all of them.
+pub(super) fn add_bins_sorted<S: DenseStore>(store: &mut S, bins: &[(i32, f64)]) {
+    store.dense_mut().prefix_sums.invalidate();
+    debug_assert!(bins.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    let is_valid_bin = |bin: &&(i32, f64)| is_valid_count(S::CountType::round_count(bin.1));
+    let first = bins.iter().find(is_valid_bin);
+    let last = bins.iter().rev().find(is_valid_bin);
+    let (Some(&(first, _)), Some(&(last, _))) = (first, last) else {
+        return;
+    };
+    let (first, last) = (clamp_index(first), clamp_index(last));
+    if !store.can_hold(first, last) {
+        // Adding the bins one by one keeps the ones that fit.
+        for &(index, count) in bins {
+            add(store, index, count);
+        }
+        return;
+    }
+    if first < store.dense().min_index || last > store.dense().max_index {
+        extend_range(store, first, last);
+    }
+    let dense = store.dense_mut();
+    for &(index, count) in bins {
+        let count = S::CountType::round_count(count);
+        if is_valid_count(count) {
+            // Collapsing stores add the bins beyond their range to the bin at its edge.
+            let index = clamp_index(index).clamp(dense.min_index, dense.max_index);
+            dense.add_to_bin((index - dense.offset) as usize, count);
+        }
+    }
+}
+
+/// The counts of the populated range, when the store keeps them as `f64`.
+pub(super) fn counts_slice<S: DenseStore>(store: &S) -> Option<&[f64]> {
+    let dense = store.dense();
+    if dense.is_empty() {
+        return Some(&[]);
+    }
+    let from = (dense.min_index - dense.offset) as usize;
+    let to = (dense.max_index - dense.offset) as usize;
+    S::CountType::as_f64_slice(&dense.counts[from..=to])
+}
+
+/// Adds the counts of the consecutive indexes from `min_index` on, extending the range once and
+/// adding them to the counts of the same indexes array to array.
+pub(super) fn merge_counts<S: DenseStore>(store: &mut S, min_index: i32, counts: &[f64]) {
+    // Zero counts at the edges must not extend, and possibly collapse, the range.
+    let (Some(first), Some(last)) = (
+        counts.iter().position(|&count| count != 0.0),
+        counts.iter().rposition(|&count| count != 0.0),
+    ) else {
+        return;
+    };
+    let counts = &counts[first..=last];
+    let min_index = min_index + first as i32;
+    let max_index = min_index + counts.len() as i32 - 1;
+    store.dense_mut().prefix_sums.invalidate();
+    if !store.can_hold(min_index, max_index) {
+        // Adding the bins one by one keeps the ones that fit.
+        for (index, &count) in (min_index..).zip(counts) {
+            add(store, index, count);
+        }
+        return;
+    }
+    if min_index < store.dense().min_index || max_index > store.dense().max_index {
+        extend_range(store, min_index, max_index);
+    }
+    let dense = store.dense_mut();
+    if min_index < dense.min_index || max_index > dense.max_index {
+        // Collapsing stores add the counts beyond their range to the bin at its edge.
+        for (index, &count) in (min_index..).zip(counts) {
+            let count = S::CountType::round_count(count);
+            if is_valid_count(count) {
+                let index = index.clamp(dense.min_index, dense.max_index);
+                dense.add_to_bin((index - dense.offset) as usize, count);
+            }
+        }
+        return;
+    }
+    let from = (min_index - dense.offset) as usize;
+    for (array_index, &count) in (from..).zip(counts) {
+        let count = S::CountType::round_count(count);
+        if is_valid_count(count) {
+            dense.add_to_bin(array_index, count);
+        }
+    }
+}
+
+/// Appends the bins of `other`, which lie above the range of the store, extending the range once
+/// to the maximum index of `other` and copying its counts in a block.
+pub(super) fn append_store<S: DenseStore>(store: &mut S, other: &S) {
+    if other.is_empty() {
+        return;
+    }
+    let (first, last) = (other.dense().min_index, other.dense().max_index);
+    let overlaps = !store.is_empty() && first <= store.dense().max_index;
+    debug_assert!(
+        !overlaps,
+        "the appended bins overlap the index range of the store"
+    );
+    if overlaps || !store.can_hold(first, last) {
+        add_bins_sorted(store, &other.snapshot_bins());
+        return;
+    }
+    store.dense_mut().prefix_sums.invalidate();
+    extend_range(store, first, last);
+    let dense = store.dense_mut();
+    let source = other.dense();
+    // Collapsing stores may not hold the whole range of other: its counts beyond the range go to
+    // the bin at its edge, which may also hold the counts the extension collapsed.
+    let (from, to) = (first.max(dense.min_index), last.min(dense.max_index));
+    if from > to {
+        let array_index = (first.clamp(dense.min_index, dense.max_index) - dense.offset) as usize;
+        dense.counts[array_index] = dense.counts[array_index].add_count(source.total_count);
+        dense.total_count += source.total_count;
+        return;
+    }
+    let (from_array_index, to_array_index) =
+        ((from - dense.offset) as usize, (to - dense.offset) as usize);
+    let mut from_count =
+        dense.counts[from_array_index].to_f64() + source.sum_counts(first, from - 1);
+    let mut to_count = source.sum_counts(to + 1, last);
+    if to_array_index == from_array_index {
+        from_count += to_count;
+        to_count = 0.0;
+    } else {
+        to_count += dense.counts[to_array_index].to_f64();
+    }
+    dense.counts[from_array_index..=to_array_index].copy_from_slice(
+        &source.counts[(from - source.offset) as usize..=(to - source.offset) as usize],
+    );
+    dense.counts[from_array_index] = dense.counts[from_array_index].add_count(from_count);
+    dense.counts[to_array_index] = dense.counts[to_array_index].add_count(to_count);
+    dense.total_count += source.total_count;
+}
+
+pub(super) fn merge_max<S: DenseStore>(store: &mut S, other: &S) {
+    let bins = other.snapshot_bins();
+    let (Some(&(first, _)), Some(&(last, _))) = (bins.first(), bins.last()) else {
+        return;
+    };
+    store.dense_mut().prefix_sums.invalidate();
+    if !store.can_hold(first, last) {
+        // Only an unbounded store gets there, and it does not collapse bins.
+        for (index, count) in bins {
+            let current_count = store.try_get_count(index).unwrap_or(0.0);
+            if count > current_count {
+                add(store, index, count - current_count);
+            }
+        }
+        return;
+    }
+    if first < store.dense().min_index || last > store.dense().max_index {
+        extend_range(store, first, last);
+    }
+    // Sum the bins of other that land in the same bin of the store before taking the maximum.
+    let dense = store.dense_mut();
+    let mut landed_bins: Vec<(i32, f64)> = Vec::with_capacity(bins.len());
+    for (index, count) in bins {
+        let index = index.clamp(dense.min_index, dense.max_index);
+        match landed_bins.last_mut() {
+            Some(bin) if bin.0 == index => bin.1 += count,
+            _ => landed_bins.push((index, count)),
+        }
+    }
+    for (index, count) in landed_bins {
+        let array_index = (index - dense.offset) as usize;
+        let current_count = dense.counts[array_index].to_f64();
+        if count > current_count {
+            dense.add_to_bin(array_index, count - current_count);
+        }
+    }
+}
+
+/// The counts of a dense store with the range of indexes they hold and the total count kept up to
+/// date as they change. The store types only differ in how they make room for indexes outside of
+/// the range.
+#[derive(Clone)]
+pub(super) struct DenseCounts<C: Count = f64> {
+    pub(super) counts: Vec<C>,
+    pub(super) offset: i32,
+    pub(super) min_index: i32,
+    pub(super) max_index: i32,
+    pub(super) total_count: f64,
+    pub(super) array_length_overhead: i32,
+    pub(super) array_length_growth_increment: i32,
+    pub(super) prefix_sums: PrefixSums,
+    pub(super) precision_warning: PrecisionWarning,
+    #[cfg(test)]
+    pub(super) num_shifts: usize,
+    #[cfg(test)]
+    pub(super) num_extensions: usize,
+}
+
+impl<C: Count> Default for DenseCounts<C> {
+    fn default() -> Self {
+        DenseCounts {
+            counts: Vec::new(),
+            offset: 0,
+            min_index: i32::MAX,
+            max_index: i32::MIN,
+            total_count: 0.0,
+            array_length_overhead: 6,
+            array_length_growth_increment: 64,
+            prefix_sums: PrefixSums::default(),
+            precision_warning: PrecisionWarning::default(),
+            #[cfg(test)]
+            num_shifts: 0,
+            #[cfg(test)]
+            num_extensions: 0,
+        }
+    }
+}
+
+impl<C: Count> DenseCounts<C> {
+    pub(super) fn is_empty(&self) -> bool {
+        self.max_index < self.min_index
+    }
+
+    /// Overwrites the counts with the ones of `src`, reusing the allocated array when it is large
+    /// enough.
+    pub(super) fn copy_from(&mut self, src: &Self) {
+        self.counts.clone_from(&src.counts);
+        self.offset = src.offset;
+        self.min_index = src.min_index;
+        self.max_index = src.max_index;
+        self.total_count = src.total_count;
+        self.array_length_overhead = src.array_length_overhead;
+        self.array_length_growth_increment = src.array_length_growth_increment;
+        self.prefix_sums.invalidate();
+        self.precision_warning = src.precision_warning.clone();
+    }
+
+    /// Exchanges the counts, their range and total with the ones of `other`, each side keeping its
+    /// settings and precision warnings.
+    pub(super) fn swap_counts(&mut self, other: &mut Self) {
+        std::mem::swap(&mut self.counts, &mut other.counts);
+        std::mem::swap(&mut self.offset, &mut other.offset);
+        std::mem::swap(&mut self.min_index, &mut other.min_index);
+        std::mem::swap(&mut self.max_index, &mut other.max_index);
+        std::mem::swap(&mut self.total_count, &mut other.total_count);
+        self.prefix_sums.invalidate();
+        other.prefix_sums.invalidate();
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.prefix_sums.invalidate();
+        self.counts.fill(C::default());
+        self.total_count = 0.0;
+        self.min_index = i32::MAX;
+        self.max_index = i32::MIN;
+        self.offset = 0;
+    }
+
+    pub(super) fn length(&self) -> i32 {
+        self.counts.len() as i32
+    }
+
+    /// The array length that holds `[min_index, max_index]` with some room to grow, `None` if it
+    /// does not fit in an `i32`.
+    pub(super) fn grown_length(&self, min_index: i32, max_index: i32) -> Option<i32> {
+        grown_length(
+            min_index,
+            max_index,
+            self.array_length_overhead,
+            self.array_length_growth_increment,
+        )
+    }
+
+    /// Sums the counts of the indexes in `[from_index, to_index]`.
+    pub(super) fn sum_counts(&self, from_index: i32, to_index: i32) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let from_array_index = i32::max(from_index - self.offset, 0);
+        let to_array_index = i32::min(to_index - self.offset, self.length() - 1) + 1;
+        (from_array_index..to_array_index)
+            .map(|array_index| self.counts[array_index as usize].to_f64())
+            .sum()
+    }
+
+    pub(super) fn total_count_recomputed(&mut self) -> f64 {
+        self.total_count = self.sum_counts(self.min_index, self.max_index);
+        self.total_count
+    }
+
+    /// Adds `count`, which the callers rounded with `Count::round_count`, to both the bin and the
+    /// total count.
+    pub(super) fn add_to_bin(&mut self, array_index: usize, count: f64) {
+        let previous_count = self.counts[array_index];
+        let new_count = previous_count.add_count(count);
+        self.precision_warning
+            .check(previous_count.to_f64(), new_count.to_f64(), count);
+        self.counts[array_index] = new_count;
+        self.total_count += count;
+    }
+
+    /// Moves the counts of `[min_index, max_index]` to a new array of `new_length` centered on the
+    /// range, dropping the counts outside of it.
+    pub(super) fn reallocate(&mut self, min_index: i32, max_index: i32, new_length: i32) {
+        self.prefix_sums.invalidate();
+        let middle_index = min_index + (max_index - min_index + 1) / 2;
+        let new_offset = middle_index - new_length / 2;
+        let mut counts = vec![C::default(); new_length as usize];
+        let kept_min_index = i32::max(self.min_index, min_index);
+        let kept_max_index = i32::min(self.max_index, max_index);
+        for index in kept_min_index..=kept_max_index {
+            counts[(index - new_offset) as usize] = self.counts[(index - self.offset) as usize];
+        }
+        self.counts = counts;
+        self.offset = new_offset;
+        if kept_min_index <= kept_max_index {
+            self.min_index = kept_min_index;
+            self.max_index = kept_max_index;
+        } else {
+            self.min_index = i32::MAX;
+            self.max_index = i32::MIN;
+        }
+        self.total_count_recomputed();
+    }
+
+    pub(super) fn reset_counts(&mut self, from_index: i32, to_index: i32) {
+        if from_index > to_index {
+            return;
+        }
+        let from = (from_index - self.offset) as usize;
+        let to = (to_index - self.offset) as usize;
+        self.counts[from..=to].fill(C::default());
+    }
+
+    /// Moves the counts by `shift` positions in the array, moving the offset the other way so that
+    /// every index keeps its count.
+    pub(super) fn shift_counts(&mut self, shift: i32) {
+        let min_array_index = self.min_index - self.offset;
+        let max_array_index = self.max_index - self.offset;
+        debug_assert!(
+            min_array_index + shift >= 0 && max_array_index + shift < self.length(),
+            "shifting by {} moves counts out of the array",
+            shift
+        );
+
+        let (from, to) = (min_array_index as usize, max_array_index as usize);
+        let destination = (min_array_index + shift) as usize;
+        self.counts.copy_within(from..=to, destination);
+        if shift > 0 {
+            self.counts[from..destination].fill(C::default());
+        } else {
+            self.counts[destination + (to - from) + 1..=to].fill(C::default());
+        }
+
+        self.offset -= shift;
+        #[cfg(test)]
+        if shift != 0 {
+            self.num_shifts += 1;
+        }
+    }
+}
@@ -1,32 +1,449 @@
 use crate::error::Error;
+use crate::index_mapping::IndexMapping;
 use crate::input::Input;
 use crate::serde;
 
 mod collapsing_highest;
 mod collapsing_lowest;
+mod dense;
 mod unbounded;
 
 use crate::output::Output;
 use crate::sketch::{Flag, FlagType};
 pub use collapsing_highest::CollapsingHighestDenseStore;
 pub use collapsing_lowest::CollapsingLowestDenseStore;
+use dense::{DenseCounts, DenseStore};
 pub use unbounded::UnboundedSizeDenseStore;
 
+/// The lowest index a store holds, counts of lower indexes are added to its bin. Together with
+/// [`MAX_INDEX`] this keeps offsets and array lengths representable as `i32`.
+pub const MIN_INDEX: i32 = i32::MIN / 2 + 1;
+/// The highest index a store holds, counts of higher indexes are added to its bin.
+pub const MAX_INDEX: i32 = i32::MAX / 2;
+
+fn clamp_index(index: i32) -> i32 {
+    index.clamp(MIN_INDEX, MAX_INDEX)
+}
+
+/// Stores only add positive and finite counts, anything else is ignored.
+pub(crate) fn is_valid_count(count: f64) -> bool {
+    count > 0.0 && count.is_finite()
+}
+
+/// Where a store places its indexes within a newly allocated array, leaving the free room on the
+/// side it is expected to grow towards.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum GrowthDirection {
+    #[default]
+    Balanced,
+    TowardHigh,
+    TowardLow,
+}
+
+/// The cumulative counts of a store, built on the first quantile lookup and dropped by any
+/// mutation of the store.
+#[derive(Clone, Default)]
+struct PrefixSums {
+    cumulative_counts: Option<Vec<(i32, f64)>>,
+    #[cfg(test)]
+    builds: usize,
+}
+
+impl PrefixSums {
+    fn invalidate(&mut self) {
+        self.cumulative_counts = None;
+    }
+
+    fn is_built(&self) -> bool {
+        self.cumulative_counts.is_some()
+    }
+
+    fn build(&mut self, cumulative_counts: Vec<(i32, f64)>) {
+        self.cumulative_counts = Some(cumulative_counts);
+        #[cfg(test)]
+        {
+            self.builds += 1;
+        }
+    }
+
+    fn quantile_index(&self, quantile: f64) -> Option<i32> {
+        let cumulative_counts = self.cumulative_counts.as_ref()?;
+        let &(_, total_count) = cumulative_counts.last()?;
+        if !(0.0..=1.0).contains(&quantile) || total_count <= 0.0 {
+            return None;
+        }
+        let rank = quantile * (total_count - 1.0);
+        let position = cumulative_counts.partition_point(|bin| bin.1 <= rank);
+        // Rounding may keep the rank out of reach of the last cumulative count.
+        let position = position.min(cumulative_counts.len() - 1);
+        Some(cumulative_counts[position].0)
+    }
+}
+
+/// Counts, while enabled, the adds that a bin did not absorb exactly because its count is too
+/// large for the precision of its type.
+#[derive(Clone, Default)]
+struct PrecisionWarning {
+    enabled: bool,
+    lossy_adds: usize,
+}
+
+impl PrecisionWarning {
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.lossy_adds = 0;
+    }
+
+    fn check(&mut self, previous_count: f64, new_count: f64, count: f64) {
+        if self.enabled && new_count - previous_count != count {
+            self.lossy_adds += 1;
+        }
+    }
+}
+
+/// Whether two total counts are equal up to the rounding of summing the same counts in another
+/// order.
+fn counts_match(total_count: f64, other_total_count: f64) -> bool {
+    (total_count - other_total_count).abs()
+        <= 1e-9 * f64::max(total_count.abs(), other_total_count.abs())
+}
+
+/// The type a dense store keeps its bin counts in.
+pub trait Count: Copy + Default + PartialEq {
+    /// The value a bin of this type takes from an added `count`, which is also what the total
+    /// count grows by.
+    fn round_count(count: f64) -> f64 {
+        count
+    }
+    fn add_count(self, count: f64) -> Self;
+    fn to_f64(self) -> f64;
+    /// The counts as `f64` without conversion, `None` for the other count types.
+    fn as_f64_slice(_counts: &[Self]) -> Option<&[f64]> {
+        None
+    }
+}
+
+impl Count for f64 {
+    fn add_count(self, count: f64) -> Self {
+        self + count
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn as_f64_slice(counts: &[Self]) -> Option<&[f64]> {
+        Some(counts)
+    }
+}
+
+/// Exact integer counts beyond the 2^53 up to which `f64` represents integers exactly. Added counts
+/// are rounded to the nearest integer.
+impl Count for u128 {
+    fn round_count(count: f64) -> f64 {
+        count.round()
+    }
+
+    fn add_count(self, count: f64) -> Self {
+        self.saturating_add(count as u128)
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Count for f32 {
+    fn add_count(self, count: f64) -> Self {
+        self + count as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
 pub trait Store {
+    type CountType: Count;
     fn add(&mut self, index: i32, count: f64);
     fn add_bin(&mut self, bin: (i32, f64));
+    fn add_bins_sorted(&mut self, bins: &[(i32, f64)]);
+    /// Overwrites the count of the bin `index` falls into, ignoring negative and non-finite counts.
+    fn set_count(&mut self, index: i32, count: f64);
+    /// Like `add` but returns an error instead of ignoring a count that is not positive and finite,
+    /// or an index too far from the range of an unbounded store for an array to hold both.
+    fn try_add(&mut self, index: i32, count: f64) -> Result<(), Error> {
+        if !is_valid_count(count) {
+            return Err(Error::InvalidArgument(
+                "The count must be positive and finite.",
+            ));
+        }
+        self.add(index, count);
+        Ok(())
+    }
+    /// Adds the bins of `store`, which may be of another type, extending the range at most once.
+    /// The counts of stores exposing them through `counts_slice` are added array to array.
     fn merge_with(&mut self, store: &mut impl Store) {
-        for bin in store.get_descending_stream() {
-            self.add_bin(bin)
+        match store.counts_slice() {
+            Some(counts) => self.merge_counts(store.get_min_index(), counts),
+            None => self.add_bins_sorted(&store.get_ascending_stream()),
+        }
+    }
+    /// The counts of the indexes in `[get_min_index(), get_max_index()]` as one slice, for the
+    /// dense stores that keep them as `f64`.
+    fn counts_slice(&self) -> Option<&[f64]> {
+        None
+    }
+    /// Adds `counts`, the counts of the consecutive indexes from `min_index` on, extending the
+    /// range at most once.
+    fn merge_counts(&mut self, min_index: i32, counts: &[f64]) {
+        let bins: Vec<(i32, f64)> = (min_index..)
+            .zip(counts.iter().copied())
+            .filter(|bin| bin.1 != 0.0)
+            .collect();
+        self.add_bins_sorted(&bins);
+    }
+    /// Adds the bins of `other` multiplied by `factor`, extending the range at most once.
+    fn merge_scaled(&mut self, other: &Self, factor: f64)
+    where
+        Self: Sized,
+    {
+        if other.is_empty() || factor <= 0.0 {
+            return;
+        }
+        let bins: Vec<(i32, f64)> = other
+            .iter_range(other.get_min_index(), other.get_max_index())
+            .filter(|bin| bin.1 != 0.0)
+            .map(|(index, count)| (index, count * factor))
+            .collect();
+        self.add_bins_sorted(&bins);
+    }
+    /// Adds an arbitrary stream of bins, in any order, extending the range at most once.
+    fn merge_iter<B: IntoIterator<Item = (i32, f64)>>(&mut self, bins: B) {
+        let mut bins: Vec<(i32, f64)> = bins.into_iter().collect();
+        bins.sort_by_key(|bin| bin.0);
+        self.add_bins_sorted(&bins);
+    }
+    /// Adds the bins of `other`, whose indexes must all be above the ones of this store, with a
+    /// single extension of the index range.
+    fn append_store(&mut self, other: &Self)
+    where
+        Self: Sized;
+    /// Adds the bins of `other`, returning an error and leaving the store unchanged if the merged
+    /// index range is too wide to be held. Collapsing stores never fail.
+    fn checked_merge(&mut self, other: &Self) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.add_bins_sorted(&other.snapshot_bins());
+        Ok(())
+    }
+    /// Replaces every non-zero count with `f(count)`, dropping the bins mapped to a count that is
+    /// not positive and finite and shrinking the index range accordingly.
+    fn map_counts<F: FnMut(f64) -> f64>(&mut self, mut f: F) {
+        for (index, count) in self.snapshot_bins() {
+            let mapped_count = f(count);
+            let mapped_count = if is_valid_count(mapped_count) {
+                mapped_count
+            } else {
+                0.0
+            };
+            self.set_count(index, mapped_count);
         }
+        shrink_to_populated(self);
     }
+    /// Zeroes every bin but the `k` with the largest counts, shrinking the index range
+    /// accordingly. Among bins with equal counts, the lower indexes are kept.
+    fn retain_top_k(&mut self, k: usize) {
+        let mut bins = self.snapshot_bins();
+        if bins.len() <= k {
+            return;
+        }
+        bins.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        for &(index, _) in &bins[k..] {
+            self.set_count(index, 0.0);
+        }
+        shrink_to_populated(self);
+    }
+    /// Raises the count of every bin to the count `other` holds for the same bin where that one is
+    /// larger, so that merging the same store again changes nothing. The bins of `other` beyond the
+    /// range of a collapsed store count as one bin at its edge.
+    fn merge_max(&mut self, other: &Self)
+    where
+        Self: Sized;
+    /// Overwrites the store with the contents and settings of `src`, reusing the allocated counts
+    /// when they are large enough.
+    fn copy_from(&mut self, src: &Self)
+    where
+        Self: Sized;
+    /// Exchanges the bins and cached total with the ones of `other`. Both stores keep their own
+    /// settings, such as the bin limit, the growth direction or the precision warnings.
+    fn swap(&mut self, other: &mut Self)
+    where
+        Self: Sized;
     fn clear(&mut self);
+    /// Removes the bins whose cumulative count range extends below `lower_quantile` or above
+    /// `upper_quantile` of the total count. Returns an error and leaves the store unchanged unless
+    /// `0 <= lower_quantile <= upper_quantile <= 1`.
+    fn truncate(&mut self, lower_quantile: f64, upper_quantile: f64) -> Result<(), Error> {
+        match truncation_range(self, lower_quantile, upper_quantile)? {
+            Some((min_index, max_index)) => {
+                self.clear_range(i32::MIN, min_index - 1);
+                self.clear_range(max_index + 1, i32::MAX);
+            }
+            None => self.clear(),
+        }
+        Ok(())
+    }
+    /// Zeroes the counts of the indexes in `[from_index, to_index]`, shrinking the index range if
+    /// either end of it is cleared.
+    fn clear_range(&mut self, from_index: i32, to_index: i32);
+    fn compact(&mut self);
+    /// Splits the store into one with the bins below `index` and one with the bins from `index` on.
+    fn split_at(self, index: i32) -> (Self, Self)
+    where
+        Self: Sized + Clone,
+    {
+        let mut lower = self;
+        let mut upper = lower.clone();
+        lower.clear_range(index, i32::MAX);
+        upper.clear_range(i32::MIN, index.saturating_sub(1));
+        (lower, upper)
+    }
+    /// Reallocates the counts to cover `[min_index, max_index]`, dropping the counts outside of the
+    /// range. Collapsing stores cover as much of it as their bin budget allows and collapse the
+    /// counts of the rest into the bin at their collapsing end, while an unbounded store is left
+    /// unchanged if the range is too wide for an array.
+    fn resize_to_range(&mut self, min_index: i32, max_index: i32);
+    /// Reallocates the counts with room for `extra_bins` more indexes, half of it on either side of
+    /// the current range, so that growing in both directions does not shift the counts until the
+    /// room is used up. Collapsing stores reserve at most their bin budget.
+    fn reserve_centered(&mut self, extra_bins: usize) {
+        if self.is_empty() || extra_bins == 0 {
+            return;
+        }
+        let (min_index, max_index) = reserved_range(
+            self.get_min_index(),
+            self.get_max_index(),
+            extra_bins,
+            self.max_num_bins()
+                .map_or(i64::MAX, |max_num_bins| max_num_bins as i64),
+        );
+        self.resize_to_range(min_index, max_index);
+    }
     fn is_empty(&self) -> bool;
     fn get_total_count(&mut self) -> f64;
+    /// The total count kept up to date as counts are added, in constant time.
+    fn total_count_cached(&self) -> f64;
+    /// Rescans the counts and resets the cached total to the result.
+    fn total_count_recomputed(&mut self) -> f64;
+    /// The exact sum of the counts, or `None` if a count is fractional or the sum does not fit in a
+    /// `u64`.
+    fn total_count_u64(&self) -> Option<u64> {
+        let mut total_count: u64 = 0;
+        for (_, count) in self.snapshot_bins() {
+            if count.fract() != 0.0 || count >= u64::MAX as f64 {
+                return None;
+            }
+            total_count = total_count.checked_add(count as u64)?;
+        }
+        Some(total_count)
+    }
+    /// Whether the cached total count is the sum of the counts. Stores with `f32` counts may break
+    /// it through rounding.
+    fn total_count_invariant_holds(&self) -> bool {
+        let total_count: f64 = self.snapshot_bins().iter().map(|bin| bin.1).sum();
+        counts_match(total_count, self.total_count_cached())
+    }
     fn get_offset(&self) -> i32;
     fn get_min_index(&self) -> i32;
     fn get_max_index(&self) -> i32;
     fn get_count(&self, i: i32) -> f64;
+    /// The count of the bin of index `index`, unlike `get_count` which takes an array index, or
+    /// `None` outside of `[get_min_index(), get_max_index()]`.
+    fn try_get_count(&self, index: i32) -> Option<f64> {
+        if self.is_empty() || index < self.get_min_index() || index > self.get_max_index() {
+            return None;
+        }
+        Some(self.get_count(index - self.get_offset()))
+    }
+    /// The lowest index of a non-zero bin, which may be above `get_min_index` once edge bins were
+    /// zeroed.
+    fn first_populated_index(&self) -> Option<i32> {
+        if self.is_empty() {
+            return None;
+        }
+        let iter = self.iter_range(self.get_min_index(), self.get_max_index());
+        (iter.min_index <= iter.max_index).then_some(iter.min_index)
+    }
+    /// The highest index of a non-zero bin, which may be below `get_max_index` once edge bins were
+    /// zeroed.
+    fn last_populated_index(&self) -> Option<i32> {
+        if self.is_empty() {
+            return None;
+        }
+        let iter = self.iter_range(self.get_min_index(), self.get_max_index());
+        (iter.min_index <= iter.max_index).then_some(iter.max_index)
+    }
+    fn num_populated_bins(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let offset = self.get_offset();
+        (self.get_min_index()..=self.get_max_index())
+            .filter(|index| self.get_count(index - offset) != 0.0)
+            .count()
+    }
+    /// The share of the indexes between the lowest and the highest index that hold a count, to
+    /// decide between dense and sparse representations. An empty store has a density of zero.
+    fn density(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let span = self.get_max_index() as i64 - self.get_min_index() as i64 + 1;
+        self.num_populated_bins() as f64 / span as f64
+    }
+    fn total_and_bounds(&self) -> Option<(f64, i32, i32)> {
+        if self.is_empty() {
+            return None;
+        }
+        let offset = self.get_offset();
+        let min_index = self.get_min_index();
+        let max_index = self.get_max_index();
+        let total_count = (min_index..=max_index)
+            .map(|index| self.get_count(index - offset))
+            .sum();
+        Some((total_count, min_index, max_index))
+    }
+    /// Returns the bin with the largest count, ties resolve to the lowest index.
+    fn max_count_bin(&self) -> Option<(i32, f64)> {
+        if self.is_empty() {
+            return None;
+        }
+        let offset = self.get_offset();
+        let mut max_bin: Option<(i32, f64)> = None;
+        for index in self.get_min_index()..=self.get_max_index() {
+            let count = self.get_count(index - offset);
+            if count > max_bin.map_or(0.0, |bin| bin.1) {
+                max_bin = Some((index, count));
+            }
+        }
+        max_bin
+    }
+    /// The number of bytes `encode` writes for this store.
+    fn serialized_size(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let (dense_encoding_size, sparse_encoding_size, num_non_empty_bins) = encoding_sizes(self);
+        let size = if dense_encoding_size <= sparse_encoding_size {
+            dense_encoding_size
+        } else {
+            serde::unsigned_var_long_encoded_length(num_non_empty_bins) + sparse_encoding_size
+        };
+        // The flag byte precedes the bins.
+        size as usize + 1
+    }
     fn encode(&self, output: &mut impl Output, store_flag_type: FlagType) -> Result<(), Error> {
         if self.is_empty() {
             return Ok(());
@@ -36,29 +453,8 @@ pub trait Store {
         let max_index = self.get_max_index();
         let offset = self.get_offset();
 
-        let mut dense_encoding_size: i64 = 0;
+        let (dense_encoding_size, sparse_encoding_size, num_non_empty_bins) = encoding_sizes(self);
         let num_bins: i64 = max_index as i64 - min_index as i64 + 1;
-        dense_encoding_size += serde::unsigned_var_long_encoded_length(num_bins);
-        dense_encoding_size += serde::signed_var_long_encoded_length(min_index as i64);
-        dense_encoding_size += serde::signed_var_long_encoded_length(1);
-
-        let mut sparse_encoding_size: i64 = 0;
-        let mut num_non_empty_bins: i64 = 0;
-        let mut previous_index: i64 = 0;
-
-        for i in min_index - offset..max_index - offset + 1 {
-            let count = self.get_count(i);
-            let count_var_double_encoded_length = serde::var_double_encoded_length(count);
-            dense_encoding_size += count_var_double_encoded_length;
-            if count != 0.0 {
-                num_non_empty_bins += 1;
-                let index: i64 = offset as i64 + i as i64;
-                sparse_encoding_size +=
-                    serde::signed_var_long_encoded_length(index - previous_index);
-                sparse_encoding_size += count_var_double_encoded_length;
-                previous_index = index;
-            }
-        }
 
         if dense_encoding_size <= sparse_encoding_size {
             BinEncodingMode::ContiguousCounts
@@ -140,29 +536,241 @@ pub trait Store {
     }
     fn get_descending_stream(&mut self) -> Vec<(i32, f64)>;
     fn get_ascending_stream(&mut self) -> Vec<(i32, f64)>;
-    fn get_descending_iter(&mut self) -> StoreIter;
-    fn get_ascending_iter(&mut self) -> StoreIter;
+    fn cumulative_counts(&mut self) -> Vec<(i32, f64)> {
+        let mut n: f64 = 0.0;
+        self.get_ascending_iter()
+            .map(|bin| {
+                n += bin.1;
+                (bin.0, n)
+            })
+            .collect()
+    }
+    /// The index of the bin holding `quantile`, found by a binary search over cumulative counts
+    /// that are cached until the store is next modified.
+    fn approximate_quantile_index_fast(&mut self, quantile: f64) -> Option<i32>;
+    /// Adds the counts of a cumulative distribution, given as `(index, cumulative_count)` pairs
+    /// in ascending index order as returned by `cumulative_counts`. Nothing is added unless the
+    /// indexes are increasing and the cumulative counts finite and non-decreasing from zero.
+    fn add_cumulative(&mut self, cumulative_counts: &[(i32, f64)]) -> Result<(), Error> {
+        let mut bins = Vec::with_capacity(cumulative_counts.len());
+        let mut previous_index: Option<i32> = None;
+        let mut previous_count = 0.0;
+        for &(index, cumulative_count) in cumulative_counts {
+            if previous_index.is_some_and(|previous_index| index <= previous_index) {
+                return Err(Error::InvalidArgument("The indexes must be increasing."));
+            }
+            if !(cumulative_count.is_finite() && cumulative_count >= previous_count) {
+                return Err(Error::InvalidArgument(
+                    "The cumulative counts must be finite and non-decreasing.",
+                ));
+            }
+            bins.push((index, cumulative_count - previous_count));
+            previous_index = Some(index);
+            previous_count = cumulative_count;
+        }
+        self.add_bins_sorted(&bins);
+        Ok(())
+    }
+    fn get_descending_stream_above(&mut self, min_count: f64) -> Vec<(i32, f64)> {
+        self.get_descending_iter()
+            .filter(|bin| bin.1 > min_count)
+            .collect()
+    }
+    fn get_ascending_stream_above(&mut self, min_count: f64) -> Vec<(i32, f64)> {
+        self.get_ascending_iter()
+            .filter(|bin| bin.1 > min_count)
+            .collect()
+    }
+    fn get_descending_iter(&mut self) -> StoreIter<'_, Self::CountType>;
+    fn get_ascending_iter(&mut self) -> StoreIter<'_, Self::CountType>;
+    fn iter_range(&self, from_index: i32, to_index: i32) -> StoreIter<'_, Self::CountType>;
+    /// Returns the populated bins in ascending index order without borrowing the store mutably.
+    fn snapshot_bins(&self) -> Vec<(i32, f64)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        self.iter_range(self.get_min_index(), self.get_max_index())
+            .filter(|bin| bin.1 != 0.0)
+            .collect()
+    }
+    /// Consumes the store, returning its populated bins in ascending index order.
+    fn into_bins(self) -> Vec<(i32, f64)>
+    where
+        Self: Sized,
+    {
+        self.snapshot_bins()
+    }
+    /// Finds the bin the cumulative `target` count falls into, in ascending index order, with the
+    /// position of `target` within the bin, from 0.0 at its lower cumulative edge to 1.0 at its
+    /// upper one. A target on the edge between two bins resolves to the lower bin.
+    fn locate_rank(&self, target: f64) -> Option<(i32, f64)> {
+        if self.is_empty() || target.is_nan() || target < 0.0 {
+            return None;
+        }
+        let mut n = 0.0;
+        for (index, count) in self.iter_range(self.get_min_index(), self.get_max_index()) {
+            if count == 0.0 {
+                continue;
+            }
+            if n + count >= target {
+                return Some((index, (target - n) / count));
+            }
+            n += count;
+        }
+        None
+    }
+    /// The index of the bin the cumulative count `fraction * total count` falls into, in a single
+    /// ascending pass.
+    fn bin_at_rank_fraction(&self, fraction: f64) -> Option<i32> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return None;
+        }
+        let target = fraction * self.total_count_cached();
+        // Rounding may keep the target just beyond the last cumulative count.
+        self.locate_rank(target)
+            .map(|bin| bin.0)
+            .or_else(|| populated_range(self).map(|range| range.1))
+    }
+    /// Redistributes the counts into `num_buckets` buckets of equal width covering
+    /// `[min_value, max_value]`. Counts are assumed to be spread uniformly over the value range of
+    /// their bin, bins straddling bucket edges are split proportionally and mass outside the range
+    /// is left out.
+    fn to_equal_width<M: IndexMapping>(
+        &mut self,
+        mapping: &M,
+        num_buckets: usize,
+        min_value: f64,
+        max_value: f64,
+    ) -> Vec<f64> {
+        let mut buckets = vec![0.0; num_buckets];
+        if num_buckets == 0 || min_value.is_nan() || max_value.is_nan() || min_value >= max_value {
+            return buckets;
+        }
+        let width = (max_value - min_value) / num_buckets as f64;
+        for (index, count) in self.get_ascending_iter().filter(|bin| bin.1 != 0.0) {
+            let lower_bound = mapping.lower_bound(index);
+            let upper_bound = mapping.upper_bound(index);
+            let density = count / (upper_bound - lower_bound);
+            let from = lower_bound.max(min_value);
+            let to = upper_bound.min(max_value);
+            if from >= to {
+                continue;
+            }
+            let first_bucket = (((from - min_value) / width) as usize).min(num_buckets - 1);
+            let last_bucket = (((to - min_value) / width) as usize).min(num_buckets - 1);
+            for (bucket, bucket_count) in buckets
+                .iter_mut()
+                .enumerate()
+                .take(last_bucket + 1)
+                .skip(first_bucket)
+            {
+                let bucket_min = min_value + bucket as f64 * width;
+                let overlap = to.min(bucket_min + width) - from.max(bucket_min);
+                if overlap > 0.0 {
+                    *bucket_count += density * overlap;
+                }
+            }
+        }
+        buckets
+    }
+    /// Iterates the populated bins in ascending index order as `(mapping.value(index), count)`.
+    fn value_iter<'a, M: IndexMapping>(
+        &'a mut self,
+        mapping: &'a M,
+    ) -> impl Iterator<Item = (f64, f64)> + 'a {
+        self.get_ascending_iter()
+            .filter(|bin| bin.1 != 0.0)
+            .map(move |(index, count)| (mapping.value(index), count))
+    }
+    /// The Shannon entropy `-Σ p ln p`, in nats, of the share `p` of the total count in each bin.
+    /// An empty store has an entropy of zero.
+    fn shannon_entropy(&mut self) -> f64 {
+        let total_count = self.get_total_count();
+        if total_count <= 0.0 {
+            return 0.0;
+        }
+        -self
+            .get_ascending_iter()
+            .filter(|bin| bin.1 > 0.0)
+            .map(|(_, count)| {
+                let p = count / total_count;
+                p * p.ln()
+            })
+            .sum::<f64>()
+    }
+    /// Renders one line per populated bin, its index followed by a bar of up to `width` columns
+    /// proportional to its count and the count itself.
+    fn ascii_histogram(&mut self, width: usize) -> String {
+        let bins: Vec<(i32, f64)> = self
+            .get_ascending_iter()
+            .filter(|bin| bin.1 != 0.0)
+            .collect();
+        let max_count = bins.iter().map(|bin| bin.1).fold(0.0, f64::max);
+        let index_width = bins
+            .iter()
+            .map(|bin| bin.0.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let mut histogram = String::new();
+        for (index, count) in bins {
+            let length = (count / max_count * width as f64).round() as usize;
+            histogram.push_str(&format!(
+                "{:>index_width$} | {:<width$} {}\n",
+                index,
+                "#".repeat(length),
+                count,
+                index_width = index_width,
+                width = width
+            ));
+        }
+        histogram
+    }
+    /// Enables or disables counting the adds that lose precision, resetting the count.
+    fn set_precision_warning(&mut self, enabled: bool);
+    /// The number of adds since precision warnings were enabled whose count was partly or fully
+    /// lost to the magnitude of the count already in the bin.
+    fn precision_warnings(&self) -> usize;
+    /// Folds the populated bins in ascending index order into an accumulator.
+    fn fold<B, F: FnMut(B, i32, f64) -> B>(&self, init: B, mut f: F) -> B {
+        if self.is_empty() {
+            return init;
+        }
+        self.iter_range(self.get_min_index(), self.get_max_index())
+            .filter(|bin| bin.1 != 0.0)
+            .fold(init, |acc, (index, count)| f(acc, index, count))
+    }
+    /// The number of bins beyond which the store collapses, `None` if it grows without limit.
+    fn max_num_bins(&self) -> Option<usize> {
+        None
+    }
+    /// The range of indexes whose counts the bin at one end of the store holds after collapsing,
+    /// `None` if nothing has been collapsed.
+    fn collapsed_range(&self) -> Option<(i32, i32)> {
+        None
+    }
+    /// Describes every inconsistency found in the store, for debugging merges and decoding.
+    fn self_check(&self) -> Vec<String>;
     fn foreach<F>(&mut self, acceptor: F)
     where
         F: FnMut(i32, f64);
 }
 
-pub struct StoreIter<'a> {
+pub struct StoreIter<'a, C: Count = f64> {
     min_index: i32,
     max_index: i32,
     offset: i32,
     desc: bool,
-    counts: &'a [f64],
+    counts: &'a [C],
 }
 
-impl<'a> StoreIter<'a> {
+impl<'a, C: Count> StoreIter<'a, C> {
     pub fn new(
         min_index: i32,
         max_index: i32,
         offset: i32,
         desc: bool,
-        counts: &'a [f64],
-    ) -> StoreIter {
+        counts: &'a [C],
+    ) -> StoreIter<'a, C> {
         StoreIter {
             desc,
             min_index,
@@ -173,7 +781,36 @@ impl<'a> StoreIter<'a> {
     }
 }
 
-impl<'a> Iterator for StoreIter<'a> {
+impl<'a, C: Count> StoreIter<'a, C> {
+    /// Iterates over the same bins in descending order instead. Only meant for an iterator created
+    /// by `iter_range`, whose first and last bins are both non-empty.
+    pub(crate) fn descending(self) -> StoreIter<'a, C> {
+        StoreIter { desc: true, ..self }
+    }
+
+    /// Iterates in ascending order over the non-empty bins of `[from_index, to_index]` that lie
+    /// within `[min_index, max_index]`.
+    pub fn in_range(
+        from_index: i32,
+        to_index: i32,
+        min_index: i32,
+        max_index: i32,
+        offset: i32,
+        counts: &'a [C],
+    ) -> StoreIter<'a, C> {
+        let mut min_index = i32::max(from_index, min_index);
+        let mut max_index = i32::min(to_index, max_index);
+        while min_index <= max_index && counts[(min_index - offset) as usize] == C::default() {
+            min_index += 1;
+        }
+        while max_index >= min_index && counts[(max_index - offset) as usize] == C::default() {
+            max_index -= 1;
+        }
+        StoreIter::new(min_index, max_index, offset, false, counts)
+    }
+}
+
+impl<'a, C: Count> Iterator for StoreIter<'a, C> {
     type Item = (i32, f64);
     fn next(&mut self) -> Option<Self::Item> {
         if self.desc {
@@ -186,13 +823,13 @@ impl<'a> Iterator for StoreIter<'a> {
 
             while self.max_index >= self.min_index {
                 let count = self.counts[(self.max_index - self.offset) as usize];
-                if count != 0.0 {
+                if count != C::default() {
                     break;
                 }
                 self.max_index -= 1;
             }
 
-            let count = self.counts[(index - self.offset) as usize];
+            let count = self.counts[(index - self.offset) as usize].to_f64();
             Some((index, count))
         } else {
             if self.min_index > self.max_index {
@@ -204,18 +841,174 @@ impl<'a> Iterator for StoreIter<'a> {
 
             while self.min_index <= self.max_index {
                 let count = self.counts[(self.min_index - self.offset) as usize];
-                if count != 0.0 {
+                if count != C::default() {
                     break;
                 }
                 self.min_index += 1;
             }
 
-            let count = self.counts[(index - self.offset) as usize];
+            let count = self.counts[(index - self.offset) as usize].to_f64();
             Some((index, count))
         }
     }
 }
 
+/// Returns the sizes of the contiguous and of the sparse encodings of the bins, the latter without
+/// its bin count, along with the number of non-empty bins.
+fn encoding_sizes<S: Store + ?Sized>(store: &S) -> (i64, i64, i64) {
+    let min_index = store.get_min_index();
+    let max_index = store.get_max_index();
+    let offset = store.get_offset();
+
+    let mut dense_encoding_size: i64 = 0;
+    let num_bins: i64 = max_index as i64 - min_index as i64 + 1;
+    dense_encoding_size += serde::unsigned_var_long_encoded_length(num_bins);
+    dense_encoding_size += serde::signed_var_long_encoded_length(min_index as i64);
+    dense_encoding_size += serde::signed_var_long_encoded_length(1);
+
+    let mut sparse_encoding_size: i64 = 0;
+    let mut num_non_empty_bins: i64 = 0;
+    let mut previous_index: i64 = 0;
+
+    for i in min_index - offset..max_index - offset + 1 {
+        let count = store.get_count(i);
+        let count_var_double_encoded_length = serde::var_double_encoded_length(count);
+        dense_encoding_size += count_var_double_encoded_length;
+        if count != 0.0 {
+            num_non_empty_bins += 1;
+            let index: i64 = offset as i64 + i as i64;
+            sparse_encoding_size += serde::signed_var_long_encoded_length(index - previous_index);
+            sparse_encoding_size += count_var_double_encoded_length;
+            previous_index = index;
+        }
+    }
+    (
+        dense_encoding_size,
+        sparse_encoding_size,
+        num_non_empty_bins,
+    )
+}
+
+/// Returns the index range `truncate` keeps, `None` if it removes every bin, or `Err` for invalid
+/// quantiles.
+fn truncation_range<S: Store + ?Sized>(
+    store: &S,
+    lower_quantile: f64,
+    upper_quantile: f64,
+) -> Result<Option<(i32, i32)>, Error> {
+    if !(0.0 <= lower_quantile && lower_quantile <= upper_quantile && upper_quantile <= 1.0) {
+        return Err(Error::InvalidArgument("Invalid quantile range."));
+    }
+    if store.is_empty() {
+        return Ok(None);
+    }
+    let bins = store.snapshot_bins();
+    let total_count: f64 = bins.iter().map(|bin| bin.1).sum();
+    let lower_rank = lower_quantile * total_count;
+    let upper_rank = upper_quantile * total_count;
+    let mut range: Option<(i32, i32)> = None;
+    let mut n = 0.0;
+    for (index, count) in bins {
+        if n >= lower_rank && n + count <= upper_rank {
+            range = Some(range.map_or((index, index), |(min_index, _)| (min_index, index)));
+        }
+        n += count;
+    }
+    Ok(range)
+}
+
+/// Widens `[min_index, max_index]` by `extra_bins` indexes split evenly on both sides, keeping the
+/// range within `max_num_bins` indexes and the valid indexes.
+fn reserved_range(
+    min_index: i32,
+    max_index: i32,
+    extra_bins: usize,
+    max_num_bins: i64,
+) -> (i32, i32) {
+    let span = max_index as i64 - min_index as i64 + 1;
+    let extra_bins = i64::min(
+        extra_bins.min(i32::MAX as usize) as i64,
+        max_num_bins - span,
+    )
+    .max(0);
+    let min_index = i64::max(min_index as i64 - extra_bins / 2, MIN_INDEX as i64);
+    let max_index = i64::min(
+        max_index as i64 + extra_bins - extra_bins / 2,
+        MAX_INDEX as i64,
+    );
+    (min_index as i32, max_index as i32)
+}
+
+/// The array length a store grows to in order to hold `[min_index, max_index]`, `None` if it does
+/// not fit in an `i32`.
+fn grown_length(
+    min_index: i32,
+    max_index: i32,
+    array_length_overhead: i32,
+    array_length_growth_increment: i32,
+) -> Option<i32> {
+    let desired_length = max_index as i64 - min_index as i64 + 1;
+    let increment = array_length_growth_increment as i64;
+    i32::try_from(((desired_length + array_length_overhead as i64 - 1) / increment + 1) * increment)
+        .ok()
+}
+
+fn populated_range<S: Store + ?Sized>(store: &S) -> Option<(i32, i32)> {
+    Some((
+        store.first_populated_index()?,
+        store.last_populated_index()?,
+    ))
+}
+
+/// Clears the empty bins at both ends of the index range, or the whole store if no bin is left.
+fn shrink_to_populated<S: Store + ?Sized>(store: &mut S) {
+    match populated_range(store) {
+        Some((min_index, _)) if min_index > store.get_min_index() => {
+            store.clear_range(store.get_min_index(), min_index - 1)
+        }
+        Some((_, max_index)) if max_index < store.get_max_index() => {
+            store.clear_range(max_index + 1, store.get_max_index())
+        }
+        Some(_) => {}
+        None => store.clear(),
+    }
+}
+
+fn check_dense_counts<C: Count>(
+    counts: &[C],
+    offset: i32,
+    min_index: i32,
+    max_index: i32,
+) -> Vec<String> {
+    let mut anomalies = Vec::new();
+    let length = counts.len() as i64;
+    let is_empty = max_index < min_index;
+    if !is_empty
+        && (min_index as i64 - (offset as i64) < 0 || max_index as i64 - offset as i64 >= length)
+    {
+        anomalies.push(format!(
+            "offset {} does not fit indexes [{}, {}] in an array of length {}",
+            offset, min_index, max_index, length
+        ));
+    }
+    for (array_index, count) in counts.iter().enumerate() {
+        let index = offset as i64 + array_index as i64;
+        let count = count.to_f64();
+        if !count.is_finite() {
+            anomalies.push(format!("non-finite count {} at index {}", count, index));
+        } else if count < 0.0 {
+            anomalies.push(format!("negative count {} at index {}", count, index));
+        }
+        if count != 0.0 && (is_empty || index < min_index as i64 || index > max_index as i64) {
+            anomalies.push(format!(
+                "populated bin at index {} outside [{}, {}]",
+                index, min_index, max_index
+            ));
+        }
+    }
+    anomalies
+}
+
 pub enum BinEncodingMode {
     IndexDeltasAndCounts = 1,
     IndexDeltas = 2,
@@ -242,6 +1035,7 @@ impl BinEncodingMode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::index_mapping::LogarithmicMapping;
 
     #[test]
     fn test_collapsing_lowest_dense_store_add() {
@@ -284,4 +1078,1405 @@ mod tests {
         assert_eq!(6, store.get_min_index());
         assert_eq!(20.0, store.get_total_count());
     }
+
+    #[test]
+    fn test_num_populated_bins() {
+        let mut store = UnboundedSizeDenseStore::new();
+        assert_eq!(0, store.num_populated_bins());
+        let indexes = vec![-20, 3, 3, 17, 42, 42, 42, 100];
+        for i in indexes {
+            store.add(i, 1.0);
+        }
+        assert_eq!(5, store.num_populated_bins());
+
+        let mut store = CollapsingLowestDenseStore::with_capacity(10).unwrap();
+        for i in 0..30 {
+            store.add(i, 1.0);
+        }
+        assert_eq!(10, store.num_populated_bins());
+        store.clear();
+        assert_eq!(0, store.num_populated_bins());
+    }
+
+    #[test]
+    fn test_max_count_bin() {
+        let mut store = CollapsingHighestDenseStore::with_capacity(100).unwrap();
+        assert_eq!(None, store.max_count_bin());
+        store.add(5, 2.0);
+        store.add(12, 7.0);
+        store.add(30, 3.0);
+        store.add(-4, 7.0);
+        store.add(12, 1.0);
+        assert_eq!(Some((12, 8.0)), store.max_count_bin());
+        store.add(-4, 1.0);
+        assert_eq!(Some((-4, 8.0)), store.max_count_bin());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.add(31, 2.0);
+
+        // The populated range sits at the upper edge of the array, so extending it shifts the counts.
+        let mut drifted = store.clone();
+        let offset = drifted.get_offset();
+        drifted.add(32, 1.0);
+        assert_ne!(offset, drifted.get_offset());
+
+        store.compact();
+        let offset = store.get_offset();
+        for i in 32..40 {
+            store.add(i, 1.0);
+        }
+        assert_eq!(offset, store.get_offset());
+        assert_eq!(11.0, store.get_total_count());
+        assert_eq!(
+            vec![(32, 1.0), (31, 2.0), (0, 1.0)],
+            store.get_descending_iter().collect::<Vec<_>>()[7..].to_vec()
+        );
+
+        let mut store = CollapsingLowestDenseStore::with_capacity(10).unwrap();
+        for i in 0..30 {
+            store.add(i, 1.0);
+        }
+        store.compact();
+        assert_eq!(20, store.get_min_index());
+        assert_eq!(29, store.get_max_index());
+        assert_eq!(30.0, store.get_total_count());
+        store.add(0, 1.0);
+        assert_eq!(31.0, store.get_total_count());
+        assert_eq!(20, store.get_min_index());
+    }
+
+    #[test]
+    fn test_get_stream_above() {
+        let mut store = CollapsingHighestDenseStore::with_capacity(100).unwrap();
+        store.add(3, 0.5);
+        store.add(7, 2.0);
+        store.add(9, 1.0);
+        store.add(12, 0.25);
+        store.add(20, 4.0);
+        assert_eq!(
+            vec![(3, 0.5), (7, 2.0), (9, 1.0), (12, 0.25), (20, 4.0)],
+            store.get_ascending_stream()
+        );
+        assert_eq!(
+            vec![(7, 2.0), (20, 4.0)],
+            store.get_ascending_stream_above(1.0)
+        );
+        assert_eq!(vec![(20, 4.0)], store.get_descending_stream_above(2.0));
+        assert_eq!(
+            vec![(20, 4.0), (9, 1.0), (7, 2.0), (3, 0.5)],
+            store.get_descending_stream_above(0.25)
+        );
+    }
+
+    fn assert_add_bins_sorted(mut expected: impl Store, mut store: impl Store) {
+        let bins = vec![
+            (-40, 1.0),
+            (-3, 2.5),
+            (0, 0.0),
+            (7, 1.0),
+            (7, 3.0),
+            (19, 4.0),
+            (63, 1.0),
+            (120, 2.0),
+        ];
+        for bin in bins.iter() {
+            expected.add_bin(*bin);
+        }
+        store.add_bins_sorted(&bins);
+        store.add_bins_sorted(&[(10, 1.0), (11, 1.0)]);
+        expected.add_bin((10, 1.0));
+        expected.add_bin((11, 1.0));
+        assert_eq!(expected.get_min_index(), store.get_min_index());
+        assert_eq!(expected.get_max_index(), store.get_max_index());
+        assert_eq!(
+            expected.get_ascending_iter().collect::<Vec<_>>(),
+            store.get_ascending_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_add_bins_sorted() {
+        assert_add_bins_sorted(
+            UnboundedSizeDenseStore::new(),
+            UnboundedSizeDenseStore::new(),
+        );
+        assert_add_bins_sorted(
+            CollapsingLowestDenseStore::with_capacity(50).unwrap(),
+            CollapsingLowestDenseStore::with_capacity(50).unwrap(),
+        );
+        assert_add_bins_sorted(
+            CollapsingHighestDenseStore::with_capacity(50).unwrap(),
+            CollapsingHighestDenseStore::with_capacity(50).unwrap(),
+        );
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add_bins_sorted(&[]);
+        store.add_bins_sorted(&[(3, 0.0)]);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_unbounded_size_dense_store_with_capacity() {
+        let mut store = UnboundedSizeDenseStore::with_capacity(1000).unwrap();
+        assert!(store.is_empty());
+        store.add(0, 1.0);
+        let offset = store.get_offset();
+        for i in -480..480 {
+            store.add(i, 1.0);
+        }
+        assert_eq!(offset, store.get_offset());
+        assert_eq!(961.0, store.get_total_count());
+
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        let offset = store.get_offset();
+        for i in -480..480 {
+            store.add(i, 1.0);
+        }
+        assert_ne!(offset, store.get_offset());
+        assert!(UnboundedSizeDenseStore::with_capacity(2147483648).is_err());
+    }
+
+    #[test]
+    fn test_total_and_bounds() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(20).unwrap();
+        assert_eq!(None, store.total_and_bounds());
+        for i in [-7, 3, 3, 12, 25, 40] {
+            store.add(i, 1.5);
+        }
+        assert_eq!(
+            Some((
+                store.get_total_count(),
+                store.get_min_index(),
+                store.get_max_index()
+            )),
+            store.total_and_bounds()
+        );
+        assert_eq!(Some((9.0, 21, 40)), store.total_and_bounds());
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut store1 = UnboundedSizeDenseStore::new();
+        store1.add(3, 1.0);
+        let mut store2 = UnboundedSizeDenseStore::new();
+        store2.add(-5, 2.0);
+        store2.add(80, 1.0);
+        store1.swap(&mut store2);
+        assert_eq!(vec![(-5, 2.0), (80, 1.0)], store1.get_ascending_stream());
+        assert_eq!(vec![(3, 1.0)], store2.get_ascending_stream());
+        assert_eq!(3.0, store1.get_total_count());
+    }
+
+    #[test]
+    fn test_cumulative_counts() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(100).unwrap();
+        assert!(store.cumulative_counts().is_empty());
+        for (index, count) in [(4, 1.0), (-2, 2.5), (9, 0.5), (4, 3.0), (30, 1.0)] {
+            store.add(index, count);
+        }
+        let cumulative_counts = store.cumulative_counts();
+        assert_eq!(
+            vec![(-2, 2.5), (4, 6.5), (9, 7.0), (30, 8.0)],
+            cumulative_counts
+        );
+        assert!(cumulative_counts
+            .windows(2)
+            .all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(store.get_total_count(), cumulative_counts.last().unwrap().1);
+    }
+
+    #[test]
+    fn test_unbounded_size_dense_store_f32() {
+        let mut store = UnboundedSizeDenseStore::new();
+        let mut store_f32 = UnboundedSizeDenseStore::<f32>::default();
+        for (index, count) in [(17, 1.0), (-3, 2.5), (40, 0.125), (17, 3.0), (-60, 7.0)] {
+            store.add(index, count);
+            store_f32.add(index, count);
+        }
+        assert_eq!(store.get_total_count(), store_f32.get_total_count());
+        assert_eq!(store.get_min_index(), store_f32.get_min_index());
+        assert_eq!(store.get_max_index(), store_f32.get_max_index());
+        assert_eq!(
+            store.get_ascending_iter().collect::<Vec<_>>(),
+            store_f32.get_ascending_iter().collect::<Vec<_>>()
+        );
+
+        // Beyond 2^24, f32 counts cannot represent every integer anymore.
+        store.add(0, 16777216.0);
+        store_f32.add(0, 16777216.0);
+        store.add(0, 1.0);
+        store_f32.add(0, 1.0);
+        assert_eq!(16777217.0, store.get_count(0 - store.get_offset()));
+        assert_eq!(16777216.0, store_f32.get_count(0 - store_f32.get_offset()));
+    }
+
+    #[test]
+    fn test_iter_range() {
+        let mut store = CollapsingHighestDenseStore::with_capacity(200).unwrap();
+        assert_eq!(0, store.iter_range(-100, 100).count());
+        for index in [-20, -3, 0, 4, 5, 17, 60] {
+            store.add(index, 1.0 + index as f64 / 100.0);
+        }
+        let bins: Vec<(i32, f64)> = store.get_ascending_iter().collect();
+        for (from_index, to_index) in [(-50, 2), (-3, 5), (1, 16), (6, 16), (50, 100), (70, 80)] {
+            let expected: Vec<(i32, f64)> = bins
+                .iter()
+                .copied()
+                .filter(|bin| from_index <= bin.0 && bin.0 <= to_index)
+                .collect();
+            assert_eq!(
+                expected,
+                store.iter_range(from_index, to_index).collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(0, store.iter_range(6, 16).count());
+        assert_eq!(
+            bins,
+            store.iter_range(i32::MIN, i32::MAX).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_merge_scaled() {
+        let mut other = UnboundedSizeDenseStore::new();
+        for index in 1000..1010 {
+            other.add(index, index as f64);
+        }
+        other.add(1020, 0.5);
+
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.add(3, 2.0);
+        let mut reference = store.clone();
+        let mut expected = store.clone();
+        store.merge_scaled(&other, 2.5);
+
+        let mut scaled = UnboundedSizeDenseStore::new();
+        for (index, count) in other.clone().get_ascending_stream() {
+            scaled.add(index, count * 2.5);
+        }
+        reference.merge_with(&mut scaled);
+        assert_eq!(
+            reference.get_ascending_stream(),
+            store.get_ascending_stream()
+        );
+        assert_eq!(1.0 + 2.0 + 2.5 * (10045.0 + 0.5), store.get_total_count());
+
+        // A single extension over the union of both ranges.
+        expected.add_bins_sorted(&[(1000, 1.0), (1020, 1.0)]);
+        assert_eq!(expected.get_offset(), store.get_offset());
+
+        let mut other = CollapsingLowestDenseStore::with_capacity(16).unwrap();
+        other.add(-5, 1.0);
+        other.add(30, 2.0);
+        let mut lowest = CollapsingLowestDenseStore::with_capacity(16).unwrap();
+        lowest.add(0, 1.0);
+        lowest.merge_scaled(&other, 2.0);
+        lowest.merge_scaled(&other, 0.0);
+        assert_eq!(7.0, lowest.get_total_count());
+        assert_eq!(vec![(15, 3.0), (30, 4.0)], lowest.get_ascending_stream());
+    }
+
+    #[test]
+    fn test_merge_iter() {
+        let bins = [
+            (7, 1.0),
+            (-3, 2.0),
+            (120, 0.5),
+            (7, 3.0),
+            (0, -1.0),
+            (40, f64::NAN),
+        ];
+        let mut stores: (UnboundedSizeDenseStore, CollapsingLowestDenseStore) = (
+            UnboundedSizeDenseStore::new(),
+            CollapsingLowestDenseStore::with_capacity(1024).unwrap(),
+        );
+        stores.0.add(10, 1.0);
+        stores.1.add(10, 1.0);
+        let mut expected = stores.clone();
+        for bin in bins {
+            expected.0.add_bin(bin);
+            expected.1.add_bin(bin);
+        }
+
+        stores.0.merge_iter(bins);
+        stores
+            .1
+            .merge_iter(bins.iter().copied().filter(|bin| bin.0 != 120));
+        stores.1.merge_iter(std::iter::once((120, 0.5)));
+        assert_eq!(
+            expected.0.get_ascending_stream(),
+            stores.0.get_ascending_stream()
+        );
+        assert_eq!(
+            expected.1.get_ascending_stream(),
+            stores.1.get_ascending_stream()
+        );
+        assert_eq!(7.5, stores.0.total_count_cached());
+        assert!(stores.0.self_check().is_empty());
+        assert!(stores.1.self_check().is_empty());
+    }
+
+    #[test]
+    fn test_total_count_invariant_through_collapses() {
+        assert_total_count_invariant(CollapsingLowestDenseStore::with_capacity(32).unwrap());
+        assert_total_count_invariant(CollapsingHighestDenseStore::with_capacity(32).unwrap());
+        assert_total_count_invariant(UnboundedSizeDenseStore::new());
+    }
+
+    fn assert_total_count_invariant(mut store: impl Store) {
+        let mut total_count = 0.0;
+        for i in 0..500 {
+            // Alternate ends so that both directions collapse or shift.
+            let index = if i % 2 == 0 { i * 3 } else { -i * 7 };
+            let count = 0.1 * (i % 13 + 1) as f64;
+            store.add(index, count);
+            total_count += count;
+            assert!(
+                store.total_count_invariant_holds(),
+                "after adding {}",
+                index
+            );
+            assert!(counts_match(total_count, store.get_total_count()));
+        }
+        store.add_bins_sorted(&[(-100_000, 1.5), (0, 2.0), (100_000, 2.5)]);
+        assert!(store.total_count_invariant_holds());
+        assert!(counts_match(total_count + 6.0, store.get_total_count()));
+    }
+
+    #[test]
+    fn test_merge_max() {
+        let mut store = CollapsingHighestDenseStore::with_capacity(256).unwrap();
+        for index in -20..40 {
+            store.add(index, (index % 7 + 1) as f64);
+        }
+        let copy = store.clone();
+        let bins = store.get_ascending_stream();
+        store.merge_max(&copy);
+        assert_eq!(bins, store.get_ascending_stream());
+        store.merge_max(&copy);
+        assert_eq!(bins, store.get_ascending_stream());
+
+        let mut summed = copy.clone();
+        summed.merge_scaled(&copy, 1.0);
+        assert_eq!(2.0 * store.get_total_count(), summed.get_total_count());
+
+        let mut other = CollapsingHighestDenseStore::with_capacity(256).unwrap();
+        other.add(-30, 2.0);
+        other.add(0, 0.5);
+        other.add(6, 10.0);
+        store.merge_max(&other);
+        assert_eq!(Some((-30, 2.0)), store.get_ascending_iter().next());
+        assert_eq!(1.0, store.get_count(0 - store.get_offset()));
+        assert_eq!(10.0, store.get_count(6 - store.get_offset()));
+        assert_eq!(
+            copy.total_count_cached() + 2.0 + 3.0,
+            store.total_count_cached()
+        );
+        assert_merge_max_collapsed(CollapsingLowestDenseStore::with_capacity(8).unwrap());
+        assert_merge_max_collapsed(CollapsingHighestDenseStore::with_capacity(8).unwrap());
+    }
+
+    fn assert_merge_max_collapsed(mut store: impl Store + Clone) {
+        let mut other = store.clone();
+        for index in 0..20 {
+            store.add(index, 1.0);
+            other.add(index, 2.0);
+        }
+        assert!(store.collapsed_range().is_some());
+        store.merge_max(&other);
+        let bins = store.get_ascending_stream();
+        assert_eq!(other.get_ascending_stream(), bins);
+        assert_eq!(40.0, store.total_count_cached());
+        store.merge_max(&other);
+        assert_eq!(bins, store.get_ascending_stream());
+        assert_eq!(40.0, store.get_total_count());
+        assert_eq!(40.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_copy_from_collapsing() {
+        let mut src = CollapsingLowestDenseStore::with_capacity(32).unwrap();
+        for index in 0..100 {
+            src.add(index, 1.0);
+        }
+        let mut store = CollapsingLowestDenseStore::with_capacity(1024).unwrap();
+        store.add(-500, 1.0);
+        store.copy_from(&src);
+        assert_eq!(src.get_ascending_stream(), store.get_ascending_stream());
+        // The bin budget is copied too.
+        store.add(-1000, 1.0);
+        assert_eq!(src.get_min_index(), store.get_min_index());
+        assert_eq!(101.0, store.get_total_count());
+    }
+
+    #[test]
+    fn test_value_iter() {
+        let mapping = LogarithmicMapping::with_relative_accuracy(1e-2).unwrap();
+        let mut store = CollapsingHighestDenseStore::with_capacity(128).unwrap();
+        for value in [0.5, 1.0, 3.0, 3.01, 250.0, 1e4] {
+            store.add(mapping.index(value), value);
+        }
+        let bins = store.get_ascending_stream();
+        let values: Vec<(f64, f64)> = store.value_iter(&mapping).collect();
+        assert_eq!(bins.len(), values.len());
+        assert!(values.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for ((index, count), (value, value_count)) in bins.into_iter().zip(values) {
+            assert_eq!(mapping.value(index), value);
+            assert_eq!(count, value_count);
+        }
+    }
+
+    #[test]
+    fn test_locate_rank() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(1, 2.0);
+        store.add(3, 4.0);
+        store.add(4, 4.0);
+        assert_eq!(Some((1, 0.0)), store.locate_rank(0.0));
+        assert_eq!(Some((1, 0.5)), store.locate_rank(1.0));
+        assert_eq!(Some((1, 1.0)), store.locate_rank(2.0));
+        assert_eq!(Some((3, 0.125)), store.locate_rank(2.5));
+        assert_eq!(Some((3, 1.0)), store.locate_rank(6.0));
+        assert_eq!(Some((4, 0.5)), store.locate_rank(8.0));
+        assert_eq!(Some((4, 1.0)), store.locate_rank(10.0));
+        assert_eq!(None, store.locate_rank(10.5));
+        assert_eq!(None, store.locate_rank(-1.0));
+        assert_eq!(None, UnboundedSizeDenseStore::new().locate_rank(0.0));
+    }
+
+    #[test]
+    fn test_bin_at_rank_fraction() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(64).unwrap();
+        assert_eq!(None, store.bin_at_rank_fraction(0.5));
+        store.add(-2, 3.0);
+        store.add(5, 1.0);
+        store.add(6, 2.0);
+        store.add(9, 4.0);
+        assert_eq!(Some(-2), store.bin_at_rank_fraction(0.0));
+        assert_eq!(Some(-2), store.bin_at_rank_fraction(0.3));
+        assert_eq!(Some(6), store.bin_at_rank_fraction(0.5));
+        assert_eq!(Some(9), store.bin_at_rank_fraction(0.61));
+        assert_eq!(Some(9), store.bin_at_rank_fraction(1.0));
+        assert_eq!(None, store.bin_at_rank_fraction(1.1));
+        assert_eq!(None, store.bin_at_rank_fraction(f64::NAN));
+
+        let mut store = UnboundedSizeDenseStore::new();
+        for _ in 0..10 {
+            store.add(7, 0.1);
+        }
+        assert_eq!(Some(7), store.bin_at_rank_fraction(1.0));
+    }
+
+    #[test]
+    fn test_total_count_u64() {
+        let mut store = UnboundedSizeDenseStore::new();
+        assert_eq!(Some(0), store.total_count_u64());
+        store.add(-4, 3.0);
+        store.add(10, 1e15);
+        store.add(10, 7.0);
+        assert_eq!(Some(1_000_000_000_000_010), store.total_count_u64());
+
+        let mut fractional = store.clone();
+        fractional.add(2, 0.5);
+        assert_eq!(None, fractional.total_count_u64());
+        fractional.add(2, 0.5);
+        assert_eq!(Some(1_000_000_000_000_011), fractional.total_count_u64());
+
+        store.add(0, 1.8446e19);
+        assert_eq!(None, store.total_count_u64());
+        let mut huge = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        huge.add(0, 2e19);
+        assert_eq!(None, huge.total_count_u64());
+    }
+
+    #[test]
+    fn test_try_get_count() {
+        assert_try_get_count(UnboundedSizeDenseStore::new());
+        assert_try_get_count(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_try_get_count(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_try_get_count(mut store: impl Store) {
+        assert_eq!(None, store.try_get_count(0));
+        store.add(-7, 2.0);
+        store.add(3, 1.5);
+        store.add(12, 4.0);
+        assert_eq!(Some(2.0), store.try_get_count(-7));
+        assert_eq!(Some(1.5), store.try_get_count(3));
+        assert_eq!(Some(0.0), store.try_get_count(4));
+        assert_eq!(Some(4.0), store.try_get_count(12));
+        assert_eq!(None, store.try_get_count(-8));
+        assert_eq!(None, store.try_get_count(13));
+        assert_eq!(None, store.try_get_count(i32::MIN));
+        assert_eq!(None, store.try_get_count(i32::MAX));
+    }
+
+    #[test]
+    fn test_from_cumulative() {
+        let mut store = UnboundedSizeDenseStore::new();
+        for (index, count) in [(-12, 1.0), (-3, 2.5), (0, 4.0), (8, 0.5), (30, 3.0)] {
+            store.add(index, count);
+        }
+        let cumulative_counts = store.cumulative_counts();
+        let mut restored = UnboundedSizeDenseStore::from_cumulative(&cumulative_counts).unwrap();
+        assert_eq!(
+            store.get_ascending_stream(),
+            restored.get_ascending_stream()
+        );
+        assert_eq!(11.0, restored.total_count_cached());
+
+        let mut collapsing = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        collapsing.add_cumulative(&cumulative_counts).unwrap();
+        assert_eq!(11.0, collapsing.get_total_count());
+
+        assert!(UnboundedSizeDenseStore::from_cumulative(&[])
+            .unwrap()
+            .is_empty());
+        for invalid in [
+            vec![(1, 1.0), (1, 2.0)],
+            vec![(1, 1.0), (0, 2.0)],
+            vec![(1, 2.0), (2, 1.0)],
+            vec![(1, -1.0)],
+            vec![(1, 1.0), (2, f64::NAN)],
+            vec![(1, 1.0), (2, f64::INFINITY)],
+        ] {
+            assert!(UnboundedSizeDenseStore::from_cumulative(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reserve_centered_within_budget() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(100).unwrap();
+        store.reserve_centered(50);
+        assert!(store.is_empty());
+        for index in 0..80 {
+            store.add(index, 1.0);
+        }
+        let bins = store.get_ascending_stream();
+        store.reserve_centered(1000);
+        assert_eq!(bins, store.get_ascending_stream());
+        assert_eq!(80.0, store.total_count_cached());
+        // The 20 bins left in the budget are split around the range.
+        let offset = store.get_offset();
+        store.add(-10, 1.0);
+        store.add(89, 1.0);
+        assert_eq!(offset, store.get_offset());
+        assert_eq!((-10, 89), (store.get_min_index(), store.get_max_index()));
+    }
+
+    #[test]
+    fn test_density() {
+        let mut store = UnboundedSizeDenseStore::new();
+        assert_eq!(0.0, store.density());
+        for index in -10..10 {
+            store.add(index, 1.0);
+        }
+        assert_eq!(1.0, store.density());
+
+        let mut sparse = CollapsingLowestDenseStore::with_capacity(4096).unwrap();
+        for index in (0..1000).step_by(100) {
+            sparse.add(index, 1.0);
+        }
+        assert!((sparse.density() - 10.0 / 901.0).abs() < 1e-12);
+        sparse.set_count(0, 0.0);
+        assert!((sparse.density() - 9.0 / 901.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_checked_merge() {
+        let mut low = UnboundedSizeDenseStore::new();
+        low.add(MIN_INDEX + 10, 1.0);
+        let mut high = UnboundedSizeDenseStore::new();
+        high.add(MAX_INDEX - 10, 2.0);
+        assert!(low.checked_merge(&high).is_err());
+        assert_eq!(low.snapshot_bins(), vec![(MIN_INDEX + 10, 1.0)]);
+
+        let mut near = UnboundedSizeDenseStore::new();
+        near.add(MIN_INDEX + 20, 2.0);
+        low.checked_merge(&near).unwrap();
+        assert_eq!(
+            low.snapshot_bins(),
+            vec![(MIN_INDEX + 10, 1.0), (MIN_INDEX + 20, 2.0)]
+        );
+
+        // Indexes the array cannot reach together with the range are ignored rather than panic.
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(MAX_INDEX, 1.0);
+        store.add(MIN_INDEX, 1.0);
+        assert!(store.try_add(MIN_INDEX, 1.0).is_err());
+        store.add_bins_sorted(&[(MIN_INDEX, 1.0), (MAX_INDEX - 1, 2.0)]);
+        store.set_count(MIN_INDEX, 1.0);
+        store.resize_to_range(MIN_INDEX, MAX_INDEX);
+        assert_eq!(
+            vec![(MAX_INDEX - 1, 2.0), (MAX_INDEX, 1.0)],
+            store.snapshot_bins()
+        );
+        assert_eq!(3.0, store.total_count_cached());
+        assert!(store.self_check().is_empty());
+
+        let mut low = CollapsingLowestDenseStore::with_capacity(16).unwrap();
+        low.add(MIN_INDEX + 10, 1.0);
+        let mut high = CollapsingLowestDenseStore::with_capacity(16).unwrap();
+        high.add(MAX_INDEX - 10, 2.0);
+        low.checked_merge(&high).unwrap();
+        assert_eq!(low.get_total_count(), 3.0);
+        assert_eq!(low.get_max_index(), MAX_INDEX - 10);
+
+        let mut low = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        low.add(MIN_INDEX + 10, 1.0);
+        let mut high = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        high.add(MAX_INDEX - 10, 2.0);
+        low.checked_merge(&high).unwrap();
+        assert_eq!(low.get_total_count(), 3.0);
+        assert_eq!(low.get_min_index(), MIN_INDEX + 10);
+    }
+
+    #[test]
+    fn test_append_store() {
+        assert_append_store(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_append_store(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+        assert_append_store(UnboundedSizeDenseStore::new());
+        // The appended range exceeds the bin budget.
+        assert_append_store(CollapsingLowestDenseStore::with_capacity(16).unwrap());
+        assert_append_store(CollapsingHighestDenseStore::with_capacity(16).unwrap());
+    }
+
+    fn assert_append_store<S: Store + Clone>(empty: S) {
+        let mut low = empty.clone();
+        let mut high = empty.clone();
+        for index in -40..10 {
+            low.add(index, (index + 41) as f64);
+        }
+        for index in (12..60).step_by(3) {
+            high.add(index, 2.0);
+        }
+        let mut merged = low.clone();
+        merged.merge_with(&mut high.clone());
+        let mut appended = low.clone();
+        appended.append_store(&high);
+        assert_eq!(appended.snapshot_bins(), merged.snapshot_bins());
+        assert_eq!(appended.get_total_count(), merged.get_total_count());
+
+        let mut appended = empty.clone();
+        appended.append_store(&low);
+        appended.append_store(&empty);
+        assert_eq!(appended.snapshot_bins(), low.snapshot_bins());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "overlap")]
+    fn test_append_store_overlapping() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(5, 1.0);
+        let mut other = UnboundedSizeDenseStore::new();
+        other.add(5, 1.0);
+        store.append_store(&other);
+    }
+
+    #[test]
+    fn test_retain_top_k() {
+        assert_retain_top_k(CollapsingLowestDenseStore::with_capacity(1024).unwrap());
+        assert_retain_top_k(CollapsingHighestDenseStore::with_capacity(1024).unwrap());
+        assert_retain_top_k(UnboundedSizeDenseStore::new());
+    }
+
+    fn assert_retain_top_k(mut store: impl Store) {
+        for index in -100..100 {
+            store.add(index, ((index * 37).rem_euclid(101) % 10) as f64 + 1.0);
+        }
+        let mut expected = store.snapshot_bins();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        expected.truncate(25);
+        expected.sort_by_key(|bin| bin.0);
+        let expected_total: f64 = expected.iter().map(|bin| bin.1).sum();
+
+        store.retain_top_k(25);
+        assert_eq!(store.snapshot_bins(), expected);
+        assert_eq!(store.num_populated_bins(), 25);
+        assert_eq!(store.get_min_index(), expected[0].0);
+        assert_eq!(store.get_max_index(), expected[24].0);
+        assert_eq!(store.get_total_count(), expected_total);
+
+        store.retain_top_k(100);
+        assert_eq!(store.snapshot_bins(), expected);
+        store.retain_top_k(0);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_map_counts() {
+        assert_map_counts(UnboundedSizeDenseStore::new());
+        assert_map_counts(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_map_counts(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_map_counts(mut store: impl Store) {
+        for (index, count) in [(-5, 0.5), (0, 2.7), (3, 0.9), (7, 4.2), (12, 0.2)] {
+            store.add(index, count);
+        }
+        store.map_counts(f64::floor);
+        assert_eq!(vec![(0, 2.0), (7, 4.0)], store.get_ascending_stream());
+        assert_eq!((0, 7), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(6.0, store.total_count_cached());
+        assert!(store.self_check().is_empty());
+
+        store.map_counts(|count| count * 1.5);
+        assert_eq!(vec![(0, 3.0), (7, 6.0)], store.get_ascending_stream());
+        assert_eq!(9.0, store.get_total_count());
+        store.map_counts(|count| if count > 4.0 { f64::NAN } else { count });
+        assert_eq!((0, 0), (store.get_min_index(), store.get_max_index()));
+        store.map_counts(|count| -count);
+        assert!(store.is_empty());
+        assert_eq!(0.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(128).unwrap();
+        assert_eq!(
+            0.0,
+            store.fold(0.0, |sum, index, count| sum + index as f64 * count)
+        );
+        for index in -20..50 {
+            store.add(index, (index % 4) as f64 + 0.5);
+        }
+        store.set_count(3, 0.0);
+
+        let mut expected = 0.0;
+        let mut num_bins = 0;
+        store.foreach(|index, count| {
+            expected += index as f64 * count;
+            num_bins += 1;
+        });
+        let weighted_sum = store.fold(0.0, |sum, index, count| sum + index as f64 * count);
+        assert_eq!(expected, weighted_sum);
+        assert_eq!(num_bins, store.fold(0, |n, _, _| n + 1));
+        assert_eq!(
+            Some(-20),
+            store.fold(None, |first, index, _| first.or(Some(index)))
+        );
+    }
+
+    #[test]
+    fn test_shannon_entropy() {
+        let mut uniform = UnboundedSizeDenseStore::new();
+        let mut spiked = UnboundedSizeDenseStore::new();
+        assert_eq!(0.0, uniform.shannon_entropy());
+        for index in 0..64 {
+            uniform.add(index, 3.0);
+            spiked.add(index, if index == 20 { 1000.0 } else { 1.0 });
+        }
+        assert!((uniform.shannon_entropy() - 64f64.ln()).abs() < 1e-12);
+        assert!(spiked.shannon_entropy() < uniform.shannon_entropy() / 2.0);
+
+        let mut single = CollapsingHighestDenseStore::with_capacity(8).unwrap();
+        single.add(5, 42.0);
+        assert_eq!(0.0, single.shannon_entropy());
+    }
+
+    #[test]
+    fn test_ascii_histogram() {
+        let mut store = CollapsingLowestDenseStore::with_capacity(64).unwrap();
+        assert_eq!("", store.ascii_histogram(10));
+        store.add(-12, 2.0);
+        store.add(3, 10.0);
+        store.add(5, 0.5);
+        store.add(9, 5.0);
+        let histogram = store.ascii_histogram(20);
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert_eq!(4, lines.len(), "{}", histogram);
+        assert_eq!(format!("  3 | {} 10", "#".repeat(20)), lines[1]);
+        assert_eq!(format!("-12 | {:<20} 2", "#".repeat(4)), lines[0]);
+        assert!(lines[2].starts_with("  5 | #  "));
+        assert_eq!(10, lines[3].matches('#').count());
+    }
+
+    #[test]
+    fn test_precision_warning() {
+        assert_precision_warning(UnboundedSizeDenseStore::new());
+        assert_precision_warning(CollapsingLowestDenseStore::with_capacity(16).unwrap());
+        assert_precision_warning(CollapsingHighestDenseStore::with_capacity(16).unwrap());
+    }
+
+    fn assert_precision_warning(mut store: impl Store) {
+        store.add(3, 1e16);
+        store.add(3, 1.0);
+        assert_eq!(0, store.precision_warnings());
+
+        store.set_precision_warning(true);
+        store.add(3, 2.0);
+        store.add(4, 1.0);
+        assert_eq!(0, store.precision_warnings());
+        store.add(3, 1.0);
+        assert_eq!(1, store.precision_warnings());
+        store.add_bin((3, 0.5));
+        store.add_bins_sorted(&[(3, 1e-3), (4, 1.0)]);
+        assert_eq!(3, store.precision_warnings());
+
+        store.set_precision_warning(false);
+        store.add(3, 1.0);
+        assert_eq!(0, store.precision_warnings());
+    }
+
+    #[test]
+    fn test_populated_indexes() {
+        assert_populated_indexes(UnboundedSizeDenseStore::new());
+        assert_populated_indexes(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_populated_indexes(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_populated_indexes(mut store: impl Store) {
+        assert_eq!(None, store.first_populated_index());
+        assert_eq!(None, store.last_populated_index());
+        for index in -5..=20 {
+            store.add(index, 1.0);
+        }
+        assert_eq!(Some(-5), store.first_populated_index());
+        assert_eq!(Some(20), store.last_populated_index());
+
+        for index in (-5..0).chain(12..=20) {
+            store.set_count(index, 0.0);
+        }
+        assert_eq!((-5, 20), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(Some(0), store.first_populated_index());
+        assert_eq!(Some(11), store.last_populated_index());
+
+        for index in 0..12 {
+            store.set_count(index, 0.0);
+        }
+        assert!(!store.is_empty());
+        assert_eq!(None, store.first_populated_index());
+        assert_eq!(None, store.last_populated_index());
+    }
+
+    #[test]
+    fn test_snapshot_bins() {
+        fn snapshot(store: &impl Store) -> Vec<(i32, f64)> {
+            store.snapshot_bins()
+        }
+        let mut store = CollapsingLowestDenseStore::with_capacity(8).unwrap();
+        assert!(snapshot(&store).is_empty());
+        for index in [3, -2, 7, 3, 20, 25] {
+            store.add(index, 1.5);
+        }
+        let bins = snapshot(&store);
+        assert_eq!(store.get_ascending_stream(), bins);
+
+        let mut store = UnboundedSizeDenseStore::<f32>::default();
+        store.add(-40, 1.0);
+        store.add(40, 2.0);
+        let bins = std::thread::scope(|scope| scope.spawn(|| snapshot(&store)).join().unwrap());
+        assert_eq!(vec![(-40, 1.0), (40, 2.0)], bins);
+        assert_eq!(store.get_ascending_stream(), bins);
+    }
+
+    #[test]
+    fn test_indexes_near_i32_min() {
+        assert_clamped_indexes(UnboundedSizeDenseStore::new(), i32::MIN, MIN_INDEX, 1);
+        assert_clamped_indexes(
+            CollapsingLowestDenseStore::with_capacity(128).unwrap(),
+            i32::MIN,
+            MIN_INDEX,
+            1,
+        );
+        assert_clamped_indexes(
+            CollapsingHighestDenseStore::with_capacity(128).unwrap(),
+            i32::MIN,
+            MIN_INDEX,
+            1,
+        );
+    }
+
+    #[test]
+    fn test_indexes_near_i32_max() {
+        assert_clamped_indexes(UnboundedSizeDenseStore::new(), i32::MAX, MAX_INDEX, -1);
+        assert_clamped_indexes(
+            CollapsingLowestDenseStore::with_capacity(128).unwrap(),
+            i32::MAX,
+            MAX_INDEX,
+            -1,
+        );
+        assert_clamped_indexes(
+            CollapsingHighestDenseStore::with_capacity(128).unwrap(),
+            i32::MAX,
+            MAX_INDEX,
+            -1,
+        );
+    }
+
+    fn assert_clamped_indexes(mut store: impl Store + Clone, extreme: i32, edge: i32, step: i32) {
+        let mut sorted = store.clone();
+        store.add(extreme, 1.0);
+        store.add(extreme + step, 1.0);
+        store.add_bin((edge, 1.0));
+        store.add(edge + 3 * step, 2.0);
+        let mut expected = vec![(edge, 3.0), (edge + 3 * step, 2.0)];
+        expected.sort_by_key(|bin| bin.0);
+        assert_eq!(expected, store.get_ascending_stream());
+        assert!(store.self_check().is_empty());
+
+        let mut bins = vec![
+            (extreme, 1.0),
+            (extreme + step, 2.0),
+            (edge + 3 * step, 2.0),
+        ];
+        bins.sort_by_key(|bin| bin.0);
+        sorted.add_bins_sorted(&bins);
+        assert_eq!(expected, sorted.get_ascending_stream());
+    }
+
+    #[test]
+    fn test_invalid_counts() {
+        assert_invalid_counts(UnboundedSizeDenseStore::new());
+        assert_invalid_counts(CollapsingLowestDenseStore::with_capacity(16).unwrap());
+        assert_invalid_counts(CollapsingHighestDenseStore::with_capacity(16).unwrap());
+    }
+
+    fn assert_invalid_counts(mut store: impl Store + Clone) {
+        let mut bins = store.clone();
+        let mut sorted = store.clone();
+        let invalid = [0.0, -1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+        for (index, count) in invalid.into_iter().enumerate() {
+            store.add(index as i32, count);
+            bins.add_bin((index as i32, count));
+            assert!(store.try_add(index as i32, count).is_err());
+        }
+        sorted.add_bins_sorted(&invalid.map(|count| (1, count)));
+        assert!(store.is_empty() && bins.is_empty() && sorted.is_empty());
+
+        store.try_add(3, 2.0).unwrap();
+        bins.add_bin((3, 2.0));
+        assert_eq!(store.get_ascending_stream(), bins.get_ascending_stream());
+    }
+
+    #[test]
+    fn test_into_bins() {
+        let mut store = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        for index in [-7, 2, 2, 9, 30, 31] {
+            store.add(index, 0.5);
+        }
+        let expected = store.clone().get_ascending_stream();
+        assert_eq!(expected, store.into_bins());
+        assert!(UnboundedSizeDenseStore::new().into_bins().is_empty());
+    }
+
+    #[test]
+    fn test_total_count_cached() {
+        assert_total_count_cached(UnboundedSizeDenseStore::new());
+        assert_total_count_cached(CollapsingLowestDenseStore::with_capacity(8).unwrap());
+        assert_total_count_cached(CollapsingHighestDenseStore::with_capacity(8).unwrap());
+    }
+
+    fn assert_total_count_cached(mut store: impl Store) {
+        assert_eq!(0.0, store.total_count_cached());
+        for index in -20..20 {
+            store.add(index, 0.25);
+        }
+        store.add_bin((100, 2.0));
+        store.add_bins_sorted(&[(-50, 1.0), (3, 1.5)]);
+        assert_eq!(14.5, store.total_count_cached());
+        assert_eq!(store.get_total_count(), store.total_count_cached());
+        assert_eq!(14.5, store.total_count_recomputed());
+        store.clear();
+        assert_eq!(0.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_merge_into_smaller_budget() {
+        let mut source = CollapsingLowestDenseStore::with_capacity(1000).unwrap();
+        let mut other = CollapsingHighestDenseStore::with_capacity(1000).unwrap();
+        for index in -400..600 {
+            source.add(index, 1.0);
+            other.add(index, 1.0);
+        }
+
+        let mut lowest = CollapsingLowestDenseStore::with_capacity(100).unwrap();
+        lowest.merge_with(&mut source);
+        assert_budget(&mut lowest, 100, 1000.0);
+        assert_eq!(599, lowest.get_max_index());
+        let mut lowest = CollapsingLowestDenseStore::with_capacity(100).unwrap();
+        lowest.add(1000, 1.0);
+        lowest.merge_scaled(&source, 1.0);
+        assert_budget(&mut lowest, 100, 1001.0);
+        assert_eq!(1000, lowest.get_max_index());
+
+        let mut highest = CollapsingHighestDenseStore::with_capacity(100).unwrap();
+        highest.merge_with(&mut other);
+        assert_budget(&mut highest, 100, 1000.0);
+        assert_eq!(-400, highest.get_min_index());
+        let mut highest = CollapsingHighestDenseStore::with_capacity(100).unwrap();
+        highest.merge_scaled(&other, 2.0);
+        assert_budget(&mut highest, 100, 2000.0);
+        assert_eq!(-400, highest.get_min_index());
+    }
+
+    #[test]
+    fn test_merge_counts() {
+        assert_merge_counts(CollapsingLowestDenseStore::with_capacity(1024).unwrap());
+        assert_merge_counts(CollapsingHighestDenseStore::with_capacity(1024).unwrap());
+        assert_merge_counts(UnboundedSizeDenseStore::new());
+        // The merged range exceeds the bin budget.
+        assert_merge_counts(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_merge_counts(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_merge_counts<S: Store + Clone>(empty: S) {
+        let mut other = UnboundedSizeDenseStore::new();
+        let mut other_f32 = UnboundedSizeDenseStore::<f32>::default();
+        for index in (-150..150).step_by(7) {
+            other.add(index, 1.5);
+            other_f32.add(index, 1.5);
+        }
+        other.clear_range(-150, -140);
+        other_f32.clear_range(-150, -140);
+        assert!(other.counts_slice().is_some());
+        assert!(other_f32.counts_slice().is_none());
+
+        let mut store = empty.clone();
+        store.add(3, 2.0);
+        store.add(500, 1.0);
+        let mut expected = store.clone();
+        // The f32 counts are merged bin by bin.
+        expected.merge_with(&mut other_f32);
+        store.merge_with(&mut other);
+        assert_eq!(expected.snapshot_bins(), store.snapshot_bins());
+        assert_eq!(expected.get_total_count(), store.total_count_cached());
+
+        let mut store = empty.clone();
+        store.merge_with(&mut UnboundedSizeDenseStore::new());
+        assert!(store.is_empty());
+    }
+
+    fn assert_budget(store: &mut impl Store, max_num_bins: i32, total_count: f64) {
+        assert!(store.get_max_index() - store.get_min_index() < max_num_bins);
+        assert_eq!(total_count, store.get_total_count());
+        assert!(store.self_check().is_empty());
+    }
+
+    #[test]
+    fn test_serialized_size() {
+        let mut shapes: Vec<Vec<(i32, f64)>> = vec![
+            vec![],
+            vec![(0, 1.0)],
+            vec![(-3, 1.0), (-2, 2.5), (-1, 1e9), (0, 4.0)],
+            vec![(-1000, 1.0), (5, 3.0), (70000, 0.125)],
+        ];
+        shapes.push((0..300).map(|index| (index * 3, index as f64)).collect());
+        for bins in shapes {
+            let mut store = UnboundedSizeDenseStore::new();
+            let mut lowest = CollapsingLowestDenseStore::with_capacity(64).unwrap();
+            for &bin in &bins {
+                store.add_bin(bin);
+                lowest.add_bin(bin);
+            }
+            assert_serialized_size(&store);
+            assert_serialized_size(&lowest);
+        }
+    }
+
+    fn assert_serialized_size(store: &impl Store) {
+        let mut output = Vec::new();
+        store.encode(&mut output, FlagType::PositiveStore).unwrap();
+        assert_eq!(output.len(), store.serialized_size());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_truncate(UnboundedSizeDenseStore::new());
+        assert_truncate(CollapsingLowestDenseStore::with_capacity(256).unwrap());
+        assert_truncate(CollapsingHighestDenseStore::with_capacity(256).unwrap());
+    }
+
+    fn assert_truncate(mut store: impl Store + Clone) {
+        for index in -50..150 {
+            store.add(index, 0.5);
+        }
+        let total_count = store.get_total_count();
+        let mut invalid = store.clone();
+        assert!(invalid.truncate(0.6, 0.4).is_err());
+        assert!(invalid.truncate(-0.1, 0.5).is_err());
+        assert_eq!(total_count, invalid.get_total_count());
+
+        store.truncate(0.05, 0.95).unwrap();
+        assert_eq!(total_count * 0.9, store.get_total_count());
+        assert_eq!(total_count * 0.9, store.total_count_cached());
+        assert_eq!((-40, 139), (store.get_min_index(), store.get_max_index()));
+        assert!(store.self_check().is_empty());
+
+        store.truncate(0.5, 0.5).unwrap();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_clear_range() {
+        assert_clear_range(UnboundedSizeDenseStore::new());
+        assert_clear_range(CollapsingLowestDenseStore::with_capacity(256).unwrap());
+        assert_clear_range(CollapsingHighestDenseStore::with_capacity(256).unwrap());
+    }
+
+    fn assert_clear_range(mut store: impl Store) {
+        for index in 0..100 {
+            store.add(index, 1.0);
+        }
+
+        store.clear_range(40, 59);
+        assert_eq!((0, 99), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(80.0, store.get_total_count());
+        assert_eq!(80.0, store.total_count_cached());
+
+        // Clearing an edge shrinks the range to the next populated bin.
+        store.clear_range(-10, 9);
+        store.clear_range(30, 39);
+        store.clear_range(95, 200);
+        assert_eq!((10, 94), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(55.0, store.total_count_cached());
+        store.clear_range(60, 94);
+        assert_eq!((10, 29), (store.get_min_index(), store.get_max_index()));
+        assert!(store.self_check().is_empty());
+
+        store.clear_range(i32::MIN, i32::MAX);
+        assert!(store.is_empty());
+        assert_eq!(0.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_split_at() {
+        assert_split_at(UnboundedSizeDenseStore::new());
+        assert_split_at(CollapsingLowestDenseStore::with_capacity(128).unwrap());
+        assert_split_at(CollapsingHighestDenseStore::with_capacity(128).unwrap());
+    }
+
+    fn assert_split_at(mut store: impl Store + Clone) {
+        for index in -30..60 {
+            store.add(index, 0.25 * (index + 31) as f64);
+        }
+        let total_count = store.get_total_count();
+        let bins = store.get_ascending_stream();
+
+        let (mut lower, mut upper) = store.clone().split_at(10);
+        assert_eq!((-30, 9), (lower.get_min_index(), lower.get_max_index()));
+        assert_eq!((10, 59), (upper.get_min_index(), upper.get_max_index()));
+        assert_eq!(
+            total_count,
+            lower.get_total_count() + upper.get_total_count()
+        );
+        let mut halves = lower.get_ascending_stream();
+        halves.extend(upper.get_ascending_stream());
+        assert_eq!(bins, halves);
+        assert!(lower.self_check().is_empty() && upper.self_check().is_empty());
+
+        let (lower, mut upper) = store.clone().split_at(-100);
+        assert!(lower.is_empty());
+        assert_eq!(bins, upper.get_ascending_stream());
+        let (mut lower, upper) = store.split_at(i32::MAX);
+        assert!(upper.is_empty());
+        assert_eq!(bins, lower.get_ascending_stream());
+    }
+
+    #[test]
+    fn test_in_range_add_keeps_range() {
+        assert_in_range_add_keeps_range(UnboundedSizeDenseStore::new());
+        assert_in_range_add_keeps_range(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_in_range_add_keeps_range(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_in_range_add_keeps_range(mut store: impl Store) {
+        store.add(-10, 1.0);
+        store.add(20, 1.0);
+        let range = (
+            store.get_min_index(),
+            store.get_max_index(),
+            store.get_offset(),
+        );
+        for i in 0..100_000 {
+            store.add(-10 + i % 31, 1.0);
+            store.add_bin((20 - i % 31, 1.0));
+        }
+        assert_eq!(
+            range,
+            (
+                store.get_min_index(),
+                store.get_max_index(),
+                store.get_offset()
+            )
+        );
+        assert_eq!(200_002.0, store.get_total_count());
+    }
+
+    #[test]
+    fn test_set_count() {
+        assert_set_count(UnboundedSizeDenseStore::new());
+        assert_set_count(CollapsingLowestDenseStore::with_capacity(64).unwrap());
+        assert_set_count(CollapsingHighestDenseStore::with_capacity(64).unwrap());
+    }
+
+    fn assert_set_count(mut store: impl Store) {
+        store.set_count(5, 0.0);
+        assert!(store.is_empty());
+        store.add(3, 1.0);
+        store.set_count(3, 4.0);
+        store.set_count(3, 2.0);
+        store.set_count(-20, 1.5);
+        store.set_count(30, 7.0);
+        store.set_count(30, -1.0);
+        store.set_count(30, f64::NAN);
+        store.set_count(-20, 0.0);
+        assert_eq!(vec![(3, 2.0), (30, 7.0)], store.get_ascending_stream());
+        assert_eq!(9.0, store.get_total_count());
+        assert_eq!(9.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_resize_to_range() {
+        let mut store = UnboundedSizeDenseStore::new();
+        for index in -10..=10 {
+            store.add(index, 1.0);
+        }
+        let mut grown = store.clone();
+        grown.resize_to_range(-1000, 1000);
+        assert_eq!(store.get_ascending_stream(), grown.get_ascending_stream());
+        let offset = grown.get_offset();
+        grown.add(-1000, 1.0);
+        grown.add(1000, 1.0);
+        assert_eq!(offset, grown.get_offset());
+
+        store.resize_to_range(-3, 20);
+        assert_eq!((-3, 10), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(14.0, store.get_total_count());
+        assert_eq!(14.0, store.total_count_cached());
+        assert!(store.self_check().is_empty());
+        store.resize_to_range(50, 60);
+        assert!(store.is_empty());
+        store.add(55, 1.0);
+        assert_eq!(vec![(55, 1.0)], store.get_ascending_stream());
+
+        let mut lowest = CollapsingLowestDenseStore::with_capacity(16).unwrap();
+        for index in 0..16 {
+            lowest.add(index, 1.0);
+        }
+        lowest.resize_to_range(-100, 10);
+        assert_eq!((0, 10), (lowest.get_min_index(), lowest.get_max_index()));
+        assert_eq!(11.0, lowest.get_total_count());
+        lowest.resize_to_range(-100, 100);
+        assert_eq!((85, 85), (lowest.get_min_index(), lowest.get_max_index()));
+        assert_eq!(11.0, lowest.get_total_count());
+        assert_eq!(11.0, lowest.total_count_cached());
+        assert_eq!(Some((MIN_INDEX, 85)), lowest.collapsed_range());
+        lowest.add(-5, 1.0);
+        assert_eq!(vec![(85, 12.0)], lowest.get_ascending_stream());
+        assert!(lowest.self_check().is_empty());
+
+        let mut highest = CollapsingHighestDenseStore::with_capacity(16).unwrap();
+        for index in 0..16 {
+            highest.add(index, 1.0);
+        }
+        highest.resize_to_range(4, 100);
+        assert_eq!((4, 15), (highest.get_min_index(), highest.get_max_index()));
+        assert_eq!(12.0, highest.get_total_count());
+        highest.resize_to_range(-100, 100);
+        assert_eq!(
+            (-85, -85),
+            (highest.get_min_index(), highest.get_max_index())
+        );
+        assert_eq!(12.0, highest.get_total_count());
+        assert!(highest.self_check().is_empty());
+    }
+
+    /// Checks that `(a + b) + c` and `a + (b + c)` hold the same bins.
+    fn merge_is_associative<S: Store + Clone>(a: &S, b: &S, c: &S) -> bool {
+        let mut left = a.clone();
+        left.merge_with(&mut b.clone());
+        left.merge_with(&mut c.clone());
+        let mut right_tail = b.clone();
+        right_tail.merge_with(&mut c.clone());
+        let mut right = a.clone();
+        right.merge_with(&mut right_tail);
+        left.get_ascending_stream() == right.get_ascending_stream()
+    }
+
+    fn random_bins(seed: &mut u64, center: i32) -> Vec<(i32, f64)> {
+        let mut next = || {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) as i32
+        };
+        let spread = next() % 300 + 1;
+        (0..next() % 50)
+            .map(|_| {
+                (
+                    center + next() % spread - spread / 2,
+                    (next() % 8 + 1) as f64,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_is_associative() {
+        let mut seed = 42;
+        for _ in 0..200 {
+            let mut stores = Vec::new();
+            for center in [-200, 0, 400] {
+                let bins = random_bins(&mut seed, center);
+                let mut unbounded = UnboundedSizeDenseStore::new();
+                let mut lowest = CollapsingLowestDenseStore::with_capacity(128).unwrap();
+                let mut highest = CollapsingHighestDenseStore::with_capacity(128).unwrap();
+                for &bin in &bins {
+                    unbounded.add_bin(bin);
+                    lowest.add_bin(bin);
+                    highest.add_bin(bin);
+                }
+                stores.push((unbounded, lowest, highest));
+            }
+            let [a, b, c] = [&stores[0], &stores[1], &stores[2]];
+            assert!(merge_is_associative(&a.0, &b.0, &c.0));
+            assert!(merge_is_associative(&a.1, &b.1, &c.1));
+            assert!(merge_is_associative(&a.2, &b.2, &c.2));
+            assert!(merge_is_associative(&c.0, &a.0, &b.0));
+            assert!(merge_is_associative(&c.1, &a.1, &b.1));
+            assert!(merge_is_associative(&c.2, &a.2, &b.2));
+        }
+    }
+
+    #[test]
+    fn test_to_equal_width() {
+        let mapping = LogarithmicMapping::with_relative_accuracy(2e-2).unwrap();
+        let mut store = UnboundedSizeDenseStore::new();
+        for i in 1..=400 {
+            store.add(mapping.index(i as f64 * 0.25), 1.0);
+        }
+
+        let (first, last) = (store.get_min_index(), store.get_max_index());
+        let (min_value, max_value) = (mapping.lower_bound(first), mapping.upper_bound(last));
+        let buckets = store.to_equal_width(&mapping, 10, min_value, max_value);
+        assert_eq!(10, buckets.len());
+        let total: f64 = buckets.iter().sum();
+        assert!((total - 400.0).abs() < 1e-9, "{}", total);
+
+        // A range cutting through bins keeps the proportional share of these bins.
+        let (min_value, max_value) = (10.0, 50.0);
+        let mut expected = 0.0;
+        for (index, count) in store.get_ascending_stream() {
+            let (lower_bound, upper_bound) =
+                (mapping.lower_bound(index), mapping.upper_bound(index));
+            let overlap = upper_bound.min(max_value) - lower_bound.max(min_value);
+            if overlap > 0.0 {
+                expected += count * overlap / (upper_bound - lower_bound);
+            }
+        }
+        let buckets = store.to_equal_width(&mapping, 7, min_value, max_value);
+        let total: f64 = buckets.iter().sum();
+        assert!((total - expected).abs() < 1e-9, "{} != {}", total, expected);
+        assert!(buckets.iter().all(|count| *count > 15.0 && *count < 30.0));
+
+        assert!(store.to_equal_width(&mapping, 0, 1.0, 2.0).is_empty());
+        assert_eq!(vec![0.0; 3], store.to_equal_width(&mapping, 3, 2.0, 1.0));
+    }
 }
@@ -1,212 +1,305 @@
 use super::*;
 use crate::serde;
 
+/// A dense store without a bin limit. Counts are kept as `f64` unless another [`Count`] type is
+/// chosen: `UnboundedSizeDenseStore::<f32>::default()` halves the memory but only represents
+/// integer counts exactly up to 2^24 per bin, while `u128` keeps integer counts exact beyond the
+/// 2^53 of `f64`.
 #[derive(Clone)]
-pub struct UnboundedSizeDenseStore {
-    counts: Vec<f64>,
-    offset: i32,
-    min_index: i32,
-    max_index: i32,
-    array_length_overhead: i32,
-    array_length_growth_increment: i32,
+pub struct UnboundedSizeDenseStore<C: Count = f64> {
+    dense: DenseCounts<C>,
+    growth_direction: GrowthDirection,
 }
 
 impl UnboundedSizeDenseStore {
     pub fn new() -> Self {
+        UnboundedSizeDenseStore::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Result<Self, Error> {
+        if capacity > 2147483647 {
+            return Err(Error::InvalidArgument(
+                "Too large capacity: should be between 0 and 2147483648.",
+            ));
+        }
+        let mut store = UnboundedSizeDenseStore::new();
+        let increment = store.dense.array_length_growth_increment as usize;
+        let length = capacity.div_ceil(increment) * increment;
+        store.dense.counts.resize(length, 0.0);
+        Ok(store)
+    }
+
+    /// Builds a store from `(index, cumulative_count)` pairs, as returned by `cumulative_counts`.
+    pub fn from_cumulative(cumulative_counts: &[(i32, f64)]) -> Result<Self, Error> {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add_cumulative(cumulative_counts)?;
+        Ok(store)
+    }
+}
+
+impl UnboundedSizeDenseStore<u128> {
+    /// The exact sum of the integer counts, which `get_total_count` rounds to an `f64`.
+    pub fn total_count_exact(&self) -> u128 {
+        if self.is_empty() {
+            return 0;
+        }
+        self.dense.counts[(self.dense.min_index - self.dense.offset) as usize
+            ..=(self.dense.max_index - self.dense.offset) as usize]
+            .iter()
+            .fold(0u128, |total_count, &count| {
+                total_count.saturating_add(count)
+            })
+    }
+}
+
+impl<C: Count> UnboundedSizeDenseStore<C> {
+    /// Biases where the counts are placed when the array is reallocated, so that a stream growing
+    /// in a known direction shifts them less often.
+    pub fn with_growth_direction(mut self, growth_direction: GrowthDirection) -> Self {
+        self.growth_direction = growth_direction;
+        self
+    }
+
+    pub fn growth_direction(&self) -> GrowthDirection {
+        self.growth_direction
+    }
+
+    fn get_new_length(&self, new_min_index: i32, new_max_index: i32) -> i32 {
+        self.dense
+            .grown_length(new_min_index, new_max_index)
+            .expect("the store only extends to ranges it can hold")
+    }
+
+    fn center_counts(&mut self, new_min_index: i32, new_max_index: i32) {
+        if new_max_index as i64 - new_min_index as i64 + 1 > self.dense.length() as i64 {
+            // Callers grow the array beforehand, but never center a range the array cannot hold.
+            let new_length = self.get_new_length(new_min_index, new_max_index);
+            self.dense.counts.resize(new_length as usize, C::default());
+        }
+        let new_offset = match self.growth_direction {
+            GrowthDirection::Balanced => {
+                let middle_index = new_min_index + (new_max_index - new_min_index + 1) / 2;
+                middle_index - self.dense.length() / 2
+            }
+            GrowthDirection::TowardHigh => new_min_index,
+            GrowthDirection::TowardLow => new_max_index - self.dense.length() + 1,
+        };
+        self.dense.shift_counts(self.dense.offset - new_offset);
+        self.dense.min_index = new_min_index;
+        self.dense.max_index = new_max_index;
+    }
+}
+
+impl<C: Count> Default for UnboundedSizeDenseStore<C> {
+    fn default() -> Self {
         UnboundedSizeDenseStore {
-            counts: Vec::new(),
-            offset: 0,
-            min_index: i32::MAX,
-            max_index: i32::MIN,
-            array_length_growth_increment: 64,
-            array_length_overhead: 6,
+            dense: DenseCounts::default(),
+            growth_direction: GrowthDirection::default(),
         }
     }
+}
+
+impl<C: Count> DenseStore for UnboundedSizeDenseStore<C> {
+    fn dense(&self) -> &DenseCounts<Self::CountType> {
+        &self.dense
+    }
 
-    fn normalize(&mut self, index: i32) -> i32 {
-        if index < self.min_index || index > self.max_index {
-            self.extend_range(index, index);
+    fn dense_mut(&mut self) -> &mut DenseCounts<Self::CountType> {
+        &mut self.dense
+    }
+
+    fn normalize(&mut self, index: i32) -> Option<usize> {
+        // Indexes within [min_index, max_index] only cost the bounds checks below.
+        let index = clamp_index(index);
+        if index < self.dense.min_index || index > self.dense.max_index {
+            if !self.can_hold(index, index) {
+                return None;
+            }
+            dense::extend_range(self, index, index);
         }
-        index - self.offset
+        serde::i32_to_usize_exact(index - self.dense.offset).ok()
     }
 
-    fn get_length(&self) -> i32 {
-        self.counts.len() as i32
+    fn can_hold(&self, min_index: i32, max_index: i32) -> bool {
+        let min_index = min_index.min(self.dense.min_index);
+        let max_index = max_index.max(self.dense.max_index);
+        self.dense.grown_length(min_index, max_index).is_some()
     }
 
     fn extend_range(&mut self, new_min_index: i32, new_max_index: i32) {
-        let new_min_index = new_min_index.min(self.min_index);
-        let new_max_index = new_max_index.max(self.max_index);
+        let new_min_index = new_min_index.min(self.dense.min_index);
+        let new_max_index = new_max_index.max(self.dense.max_index);
 
         if self.is_empty() {
             let initial_length = self.get_new_length(new_min_index, new_max_index);
-            if initial_length >= self.get_length() {
-                self.counts.resize(initial_length as usize, 0.0);
+            if initial_length >= self.dense.length() {
+                self.dense
+                    .counts
+                    .resize(initial_length as usize, C::default());
             }
-            self.offset = new_min_index;
-            self.min_index = new_min_index;
-            self.max_index = new_max_index;
-            self.adjust(new_min_index, new_max_index);
-        } else if new_min_index >= self.offset && new_max_index < self.offset + self.get_length() {
-            self.min_index = new_min_index;
-            self.max_index = new_max_index;
+            self.dense.offset = new_min_index;
+            self.dense.min_index = new_min_index;
+            self.dense.max_index = new_max_index;
+            dense::adjust(self, new_min_index, new_max_index);
+        } else if new_min_index >= self.dense.offset
+            && new_max_index < self.dense.offset + self.dense.length()
+        {
+            self.dense.min_index = new_min_index;
+            self.dense.max_index = new_max_index;
         } else {
             // To avoid shifting too often when nearing the capacity of the array, we may grow it before
             // we actually reach the capacity.
             let new_length = self.get_new_length(new_min_index, new_max_index);
-            if new_length > self.get_length() {
-                self.counts.resize(new_length as usize, 0.0);
+            if new_length > self.dense.length() {
+                self.dense.counts.resize(new_length as usize, C::default());
             }
-            self.adjust(new_min_index, new_max_index);
+            dense::adjust(self, new_min_index, new_max_index);
         }
     }
 
     fn adjust(&mut self, new_min_index: i32, new_max_index: i32) {
         self.center_counts(new_min_index, new_max_index);
     }
+}
 
-    fn get_new_length(&self, new_min_index: i32, new_max_index: i32) -> i32 {
-        let desired_length = (new_max_index as i64 - new_min_index as i64) as i32 + 1;
-        ((desired_length + self.array_length_overhead - 1) / self.array_length_growth_increment + 1)
-            * self.array_length_growth_increment
-    }
+impl<C: Count> Store for UnboundedSizeDenseStore<C> {
+    type CountType = C;
 
-    fn center_counts(&mut self, new_min_index: i32, new_max_index: i32) {
-        let middle_index = new_min_index + (new_max_index - new_min_index + 1) / 2;
-        let shift = self.offset + self.get_length() / 2 - middle_index;
-        self.shift_counts(shift);
-        self.min_index = new_min_index;
-        self.max_index = new_max_index;
-    }
-
-    fn shift_counts(&mut self, shift: i32) {
-        let min_array_index = self.min_index - self.offset;
-        let max_array_index = self.max_index - self.offset;
-
-        // System.arraycopy(counts, min_array_index, counts, min_array_index + shift, max_array_index - min_array_index + 1);
-        self.array_copy(
-            min_array_index,
-            min_array_index + shift,
-            max_array_index - min_array_index + 1,
-        );
+    fn add(&mut self, index: i32, count: f64) {
+        dense::add(self, index, count);
+    }
 
-        if shift > 0 {
-            // Arrays.fill(counts, min_array_index, min_array_index + shift, 0);
-            let from = min_array_index;
-            let to = min_array_index + shift;
-            for index in from..to {
-                self.counts[index as usize] = 0.0;
-            }
-        } else {
-            // Arrays.fill(counts, max_array_index + 1 + shift, max_array_index + 1, 0);
-            let from = max_array_index + 1 + shift;
-            let to = max_array_index + 1;
-            for index in from..to {
-                self.counts[index as usize] = 0.0;
-            }
+    fn try_add(&mut self, index: i32, count: f64) -> Result<(), Error> {
+        if !is_valid_count(count) {
+            return Err(Error::InvalidArgument(
+                "The count must be positive and finite.",
+            ));
         }
+        let index = clamp_index(index);
+        if !self.can_hold(index, index) {
+            return Err(Error::InvalidArgument(
+                "The index range is too wide to be stored.",
+            ));
+        }
+        dense::add(self, index, count);
+        Ok(())
+    }
 
-        self.offset -= shift;
+    fn add_bin(&mut self, bin: (i32, f64)) {
+        dense::add(self, bin.0, bin.1);
     }
 
-    fn array_copy(&mut self, src_pos: i32, dest_pos: i32, length: i32) {
-        if src_pos < dest_pos {
-            let mut offset = length - 1;
-            while offset >= 0 {
-                self.counts[(dest_pos + offset) as usize] =
-                    self.counts[(src_pos + offset) as usize];
-                offset -= 1;
-            }
-        } else if src_pos > dest_pos {
-            let mut offset = 0;
-            while offset < length {
-                self.counts[(dest_pos + offset) as usize] =
-                    self.counts[(src_pos + offset) as usize];
-                offset += 1;
-            }
-        }
+    fn add_bins_sorted(&mut self, bins: &[(i32, f64)]) {
+        dense::add_bins_sorted(self, bins);
     }
 
-    fn get_total_count_with_range(&mut self, from_index: i32, to_index: i32) -> f64 {
-        if self.is_empty() {
-            return 0.0;
+    fn set_count(&mut self, index: i32, count: f64) {
+        dense::set_count(self, index, count);
+    }
+
+    fn checked_merge(&mut self, other: &Self) -> Result<(), Error> {
+        if other.is_empty() {
+            return Ok(());
+        }
+        if !self.can_hold(other.dense.min_index, other.dense.max_index) {
+            return Err(Error::InvalidArgument(
+                "The merged index range is too wide to be stored.",
+            ));
         }
+        self.add_bins_sorted(&other.snapshot_bins());
+        Ok(())
+    }
 
-        let from_array_index = i32::max(from_index - self.offset, 0);
-        let to_array_index = i32::min(to_index - self.offset, self.get_length() - 1) + 1;
+    fn merge_max(&mut self, other: &Self) {
+        dense::merge_max(self, other);
+    }
 
-        let mut total_count: f64 = 0.0;
-        for array_index in from_array_index..to_array_index {
-            total_count += self.counts[array_index as usize];
-        }
-        total_count
+    fn counts_slice(&self) -> Option<&[f64]> {
+        dense::counts_slice(self)
     }
-}
 
-impl Store for UnboundedSizeDenseStore {
-    fn add(&mut self, index: i32, count: f64) {
-        if count <= 0.0 {
-            return;
-        }
+    fn merge_counts(&mut self, min_index: i32, counts: &[f64]) {
+        dense::merge_counts(self, min_index, counts);
+    }
 
-        let array_index = serde::i32_to_usize_exact(self.normalize(index));
-        match array_index {
-            Ok(index) => {
-                self.counts[index] += count;
-            }
-            _ => {}
-        }
+    fn append_store(&mut self, other: &Self) {
+        dense::append_store(self, other);
     }
 
-    fn add_bin(&mut self, bin: (i32, f64)) {
-        if bin.1 == 0.0 {
-            return;
-        }
-        let array_index = serde::i32_to_usize_exact(self.normalize(bin.0));
-        match array_index {
-            Ok(index) => {
-                self.counts[index] += bin.1;
-            }
-            _ => {}
-        }
+    fn copy_from(&mut self, src: &Self) {
+        self.dense.copy_from(&src.dense);
+        self.growth_direction = src.growth_direction;
+    }
+
+    fn swap(&mut self, other: &mut Self) {
+        self.dense.swap_counts(&mut other.dense);
     }
 
     fn clear(&mut self) {
-        self.counts.fill(0.0);
-        self.max_index = i32::MIN;
-        self.min_index = i32::MAX;
-        self.offset = 0;
+        self.dense.clear();
+    }
+
+    fn clear_range(&mut self, from_index: i32, to_index: i32) {
+        dense::clear_range(self, from_index, to_index);
+    }
+
+    fn compact(&mut self) {
+        dense::compact(self);
+    }
+
+    fn resize_to_range(&mut self, min_index: i32, max_index: i32) {
+        self.dense.prefix_sums.invalidate();
+        if min_index > max_index {
+            return;
+        }
+        let (min_index, max_index) = (clamp_index(min_index), clamp_index(max_index));
+        if let Some(new_length) = self.dense.grown_length(min_index, max_index) {
+            self.dense.reallocate(min_index, max_index, new_length);
+        }
     }
 
     fn is_empty(&self) -> bool {
-        self.max_index < self.min_index
+        self.dense.is_empty()
     }
 
     fn get_total_count(&mut self) -> f64 {
-        self.get_total_count_with_range(self.min_index, self.max_index)
+        self.dense
+            .sum_counts(self.dense.min_index, self.dense.max_index)
     }
 
     fn get_min_index(&self) -> i32 {
-        self.min_index
+        self.dense.min_index
     }
 
     fn get_max_index(&self) -> i32 {
-        self.max_index
+        self.dense.max_index
+    }
+
+    fn total_count_cached(&self) -> f64 {
+        self.dense.total_count
+    }
+
+    fn total_count_recomputed(&mut self) -> f64 {
+        self.dense.total_count_recomputed()
     }
 
     fn get_offset(&self) -> i32 {
-        self.offset
+        self.dense.offset
     }
 
     fn get_count(&self, i: i32) -> f64 {
-        self.counts[i as usize]
+        self.dense.counts[i as usize].to_f64()
+    }
+
+    fn approximate_quantile_index_fast(&mut self, quantile: f64) -> Option<i32> {
+        dense::approximate_quantile_index_fast(self, quantile)
     }
 
     fn get_descending_stream(&mut self) -> Vec<(i32, f64)> {
         let mut bins = Vec::new();
-        let mut index = self.max_index;
-        while index >= self.min_index {
-            let value = self.counts[(index - self.offset) as usize];
+        let mut index = self.dense.max_index;
+        while index >= self.dense.min_index {
+            let value = self.dense.counts[(index - self.dense.offset) as usize].to_f64();
             if value > 0.0 {
                 let bin = (index, value);
                 bins.push(bin);
@@ -218,35 +311,63 @@ impl Store for UnboundedSizeDenseStore {
 
     fn get_ascending_stream(&mut self) -> Vec<(i32, f64)> {
         let mut bins = Vec::new();
-        let mut index = self.min_index;
-        while index <= self.max_index {
-            let value = self.counts[(index - self.offset) as usize];
+        let mut index = self.dense.min_index;
+        while index <= self.dense.max_index {
+            let value = self.dense.counts[(index - self.dense.offset) as usize].to_f64();
             if value > 0.0 {
                 let bin = (index, value);
                 bins.push(bin);
             }
-            index -= 1;
+            index += 1;
         }
         bins
     }
 
-    fn get_descending_iter(&mut self) -> StoreIter {
+    fn get_descending_iter(&mut self) -> StoreIter<'_, C> {
         StoreIter::new(
-            self.min_index,
-            self.max_index,
-            self.offset,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
             true,
-            self.counts.as_slice(),
+            self.dense.counts.as_slice(),
         )
     }
 
-    fn get_ascending_iter(&mut self) -> StoreIter {
+    fn get_ascending_iter(&mut self) -> StoreIter<'_, C> {
         StoreIter::new(
-            self.min_index,
-            self.max_index,
-            self.offset,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
             false,
-            self.counts.as_slice(),
+            self.dense.counts.as_slice(),
+        )
+    }
+
+    fn iter_range(&self, from_index: i32, to_index: i32) -> StoreIter<'_, C> {
+        StoreIter::in_range(
+            from_index,
+            to_index,
+            self.dense.min_index,
+            self.dense.max_index,
+            self.dense.offset,
+            self.dense.counts.as_slice(),
+        )
+    }
+
+    fn set_precision_warning(&mut self, enabled: bool) {
+        self.dense.precision_warning.set_enabled(enabled);
+    }
+
+    fn precision_warnings(&self) -> usize {
+        self.dense.precision_warning.lossy_adds
+    }
+
+    fn self_check(&self) -> Vec<String> {
+        check_dense_counts(
+            &self.dense.counts,
+            self.dense.offset,
+            self.dense.min_index,
+            self.dense.max_index,
         )
     }
 
@@ -258,16 +379,265 @@ impl Store for UnboundedSizeDenseStore {
             return;
         }
 
-        for i in self.min_index..self.max_index {
-            let value = self.counts[(i - self.offset) as usize];
+        for i in self.dense.min_index..self.dense.max_index {
+            let value = self.dense.counts[(i - self.dense.offset) as usize].to_f64();
             if value != 0.0 {
                 acceptor(i, value);
             }
         }
 
-        let last_count = self.counts[(self.max_index - self.offset) as usize];
+        let last_count =
+            self.dense.counts[(self.dense.max_index - self.dense.offset) as usize].to_f64();
         if last_count != 0.0 {
-            acceptor(self.max_index, last_count);
+            acceptor(self.dense.max_index, last_count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_center_counts_grows_array() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.add(10, 2.0);
+        assert_eq!(64, store.dense.length());
+
+        // A range wider than the array used to shift the counts out of bounds.
+        store.center_counts(-100, 100);
+        assert!(store.dense.length() >= 201);
+        assert!(store.dense.offset <= -100 && 100 < store.dense.offset + store.dense.length());
+        store.add(-100, 1.0);
+        store.add(100, 1.0);
+        assert_eq!(
+            vec![(-100, 1.0), (0, 1.0), (10, 2.0), (100, 1.0)],
+            store.get_ascending_stream()
+        );
+    }
+
+    #[test]
+    fn test_self_check() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.add(5, 2.0);
+        assert!(store.self_check().is_empty());
+
+        store.dense.counts[(1 - store.dense.offset) as usize] = -1.0;
+        store.dense.counts[(2 - store.dense.offset) as usize] = f64::NAN;
+        store.dense.counts[(20 - store.dense.offset) as usize] = 3.0;
+        let anomalies = store.self_check();
+        assert_eq!(3, anomalies.len(), "{:?}", anomalies);
+        assert!(anomalies[0].starts_with("negative count -1 at index 1"));
+        assert!(anomalies[1].starts_with("non-finite count NaN at index 2"));
+        assert!(anomalies[2].starts_with("populated bin at index 20 outside [0, 5]"));
+
+        store.dense.offset = 3;
+        let anomalies = store.self_check();
+        assert!(anomalies[0].starts_with("offset 3 does not fit indexes [0, 5]"));
+    }
+
+    #[test]
+    fn test_total_count_recomputed() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(1, 2.0);
+        store.add(9, 3.0);
+        store.dense.total_count = 1e9;
+        assert_eq!(1e9, store.total_count_cached());
+        assert_eq!(5.0, store.total_count_recomputed());
+        assert_eq!(5.0, store.total_count_cached());
+    }
+
+    #[test]
+    fn test_in_range_add_does_not_reallocate() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(-10, 1.0);
+        store.add(20, 1.0);
+        let counts = store.dense.counts.as_ptr();
+        for i in 0..10_000 {
+            store.add(-10 + i % 31, 1.0);
+        }
+        assert_eq!(counts, store.dense.counts.as_ptr());
+        assert_eq!(64, store.dense.length());
+    }
+
+    #[test]
+    fn test_approximate_quantile_index_fast_caches_prefix_sums() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(1, 1.0);
+        store.add(3, 2.0);
+        store.add(8, 1.0);
+        assert_eq!(0, store.dense.prefix_sums.builds);
+
+        assert_eq!(Some(1), store.approximate_quantile_index_fast(0.0));
+        assert_eq!(Some(3), store.approximate_quantile_index_fast(0.5));
+        assert_eq!(Some(8), store.approximate_quantile_index_fast(1.0));
+        assert_eq!(None, store.approximate_quantile_index_fast(1.5));
+        assert_eq!(1, store.dense.prefix_sums.builds);
+
+        store.add(10, 4.0);
+        assert!(!store.dense.prefix_sums.is_built());
+        assert_eq!(Some(10), store.approximate_quantile_index_fast(0.9));
+        assert_eq!(2, store.dense.prefix_sums.builds);
+
+        store.clear();
+        assert_eq!(None, store.approximate_quantile_index_fast(0.5));
+        assert_eq!(3, store.dense.prefix_sums.builds);
+    }
+
+    #[test]
+    fn test_add_bins_sorted_extends_once() {
+        let bins: Vec<(i32, f64)> = (-100..100).map(|index| (index * 3, 1.0)).collect();
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.dense.num_extensions = 0;
+        store.add_bins_sorted(&bins);
+        assert_eq!(1, store.dense.num_extensions);
+        assert_eq!(201.0, store.get_total_count());
+
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        store.dense.num_extensions = 0;
+        for &bin in &bins {
+            store.add_bin(bin);
+        }
+        assert!(store.dense.num_extensions > 1);
+    }
+
+    #[test]
+    fn test_append_store_extends_once() {
+        let mut store = UnboundedSizeDenseStore::new();
+        store.add(0, 1.0);
+        let mut other = UnboundedSizeDenseStore::new();
+        for index in 10..1000 {
+            other.add(index, 2.0);
+        }
+        store.dense.num_extensions = 0;
+        store.append_store(&other);
+        assert_eq!(1, store.dense.num_extensions);
+        assert_eq!(1981.0, store.get_total_count());
+        assert_eq!(1981.0, store.total_count_cached());
+        assert_eq!(Some(2.0), store.try_get_count(999));
+    }
+
+    #[test]
+    fn test_growth_direction() {
+        let shifts = |growth_direction: GrowthDirection| {
+            let mut store = UnboundedSizeDenseStore::new().with_growth_direction(growth_direction);
+            for index in 0..2000 {
+                store.add(index, 1.0);
+            }
+            assert_eq!(2000.0, store.get_total_count());
+            assert!(store.self_check().is_empty());
+            store.dense.num_shifts
+        };
+        let balanced = shifts(GrowthDirection::Balanced);
+        let toward_high = shifts(GrowthDirection::TowardHigh);
+        assert!(toward_high < balanced, "{} >= {}", toward_high, balanced);
+
+        let mut store =
+            UnboundedSizeDenseStore::new().with_growth_direction(GrowthDirection::TowardLow);
+        for index in (-2000..0).rev() {
+            store.add(index, 1.0);
+        }
+        assert!(store.dense.num_shifts < balanced);
+        assert_eq!((-2000, -1), (store.get_min_index(), store.get_max_index()));
+    }
+
+    #[test]
+    fn test_copy_from() {
+        let mut src = UnboundedSizeDenseStore::new();
+        for index in -40..40 {
+            src.add(index, 1.5);
+        }
+        let mut store = UnboundedSizeDenseStore::with_capacity(512).unwrap();
+        store.add(1000, 2.0);
+        let counts = store.dense.counts.as_ptr();
+        store.copy_from(&src);
+        assert_eq!(counts, store.dense.counts.as_ptr());
+        assert_eq!(src.get_ascending_stream(), store.get_ascending_stream());
+        assert_eq!(120.0, store.total_count_cached());
+        assert!(store.self_check().is_empty());
+
+        // Copying does not share the counts.
+        store.add(0, 1.0);
+        assert_eq!(120.0, src.get_total_count());
+
+        let mut small = UnboundedSizeDenseStore::new();
+        small.copy_from(&store);
+        assert_eq!(store.get_ascending_stream(), small.get_ascending_stream());
+        assert_eq!(store.get_offset(), small.get_offset());
+    }
+
+    #[test]
+    fn test_u128_counts() {
+        let mut exact = UnboundedSizeDenseStore::<u128>::default();
+        let mut store = UnboundedSizeDenseStore::new();
+        assert_eq!(0, exact.total_count_exact());
+        let large = (1u64 << 53) as f64;
+        exact.add(4, large);
+        store.add(4, large);
+        for _ in 0..3 {
+            exact.add(4, 1.0);
+            store.add(4, 1.0);
+        }
+        exact.add(-2, 5.0);
+        assert_eq!((1u128 << 53) + 3 + 5, exact.total_count_exact());
+        assert_eq!(large, store.get_count(4 - store.dense.offset));
+        assert_eq!(
+            (1u128 << 53) + 3,
+            exact.dense.counts[(4 - exact.dense.offset) as usize]
+        );
+        assert_eq!(
+            vec![-2, 4],
+            exact
+                .snapshot_bins()
+                .iter()
+                .map(|bin| bin.0)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_u128_fractional_counts() {
+        let mut exact = UnboundedSizeDenseStore::<u128>::default();
+        exact.add(1, 0.5);
+        exact.add(1, 0.5);
+        exact.add(2, 2.4);
+        exact.add(3, 0.4);
+        exact.add_bins_sorted(&[(0, 0.2), (2, 1.6), (4, 0.3)]);
+        assert_eq!(vec![(1, 2.0), (2, 4.0)], exact.snapshot_bins());
+        assert_eq!((1, 2), (exact.get_min_index(), exact.get_max_index()));
+        assert_eq!(6, exact.total_count_exact());
+        assert_eq!(6.0, exact.total_count_cached());
+        assert!(exact.total_count_invariant_holds());
+
+        exact.set_count(2, 1.5);
+        assert_eq!(4.0, exact.total_count_cached());
+        assert!(exact.total_count_invariant_holds());
+    }
+
+    #[test]
+    fn test_reserve_centered() {
+        let mut store = UnboundedSizeDenseStore::new();
+        for index in 0..10 {
+            store.add(index, 1.0);
+        }
+        store.reserve_centered(400);
+        assert!(store.dense.length() >= 410);
+        assert_eq!((0, 9), (store.get_min_index(), store.get_max_index()));
+        assert_eq!(10.0, store.total_count_cached());
+
+        let num_shifts = store.dense.num_shifts;
+        for i in 1..=200 {
+            store.add(-i, 1.0);
+            store.add(9 + i, 1.0);
         }
+        assert_eq!(num_shifts, store.dense.num_shifts);
+        store.add(-200 - store.dense.length(), 1.0);
+        assert!(store.dense.num_shifts > num_shifts);
+        assert_eq!(411.0, store.get_total_count());
+        assert!(store.self_check().is_empty());
     }
 }
@@ -0,0 +1,149 @@
+use crate::error::Error;
+use crate::index_mapping::{IndexMapping, LogarithmicMapping};
+use crate::sketch::{ascending_bins, descending_bins, find_quantile_bin, QuantileBin, RankMode};
+use crate::store::{is_valid_count, Store, UnboundedSizeDenseStore};
+
+/// A sketch with [UDDSketch](https://arxiv.org/pdf/2004.08604.pdf) semantics: once the bins span
+/// more than `max_num_bins` indexes, `gamma` is squared and every bin is merged with its
+/// neighbour, so the relative accuracy degrades uniformly over the whole distribution instead of
+/// at one edge.
+pub struct UDDSketch {
+    index_mapping: LogarithmicMapping,
+    negative_value_store: UnboundedSizeDenseStore,
+    positive_value_store: UnboundedSizeDenseStore,
+    zero_count: f64,
+    max_num_bins: i64,
+    num_collapses: u32,
+    rank_mode: RankMode,
+}
+
+impl UDDSketch {
+    pub fn new(relative_accuracy: f64, max_num_bins: usize) -> Result<UDDSketch, Error> {
+        if max_num_bins < 2 {
+            return Err(Error::InvalidArgument(
+                "The maximum number of bins must be at least 2.",
+            ));
+        }
+        Ok(UDDSketch {
+            index_mapping: LogarithmicMapping::with_relative_accuracy(relative_accuracy)?,
+            negative_value_store: UnboundedSizeDenseStore::new(),
+            positive_value_store: UnboundedSizeDenseStore::new(),
+            zero_count: 0.0,
+            max_num_bins: max_num_bins as i64,
+            num_collapses: 0,
+            rank_mode: RankMode::default(),
+        })
+    }
+
+    /// The current relative accuracy, which grows with every collapse.
+    pub fn get_relative_accuracy(&self) -> f64 {
+        self.index_mapping.get_relative_accuracy()
+    }
+
+    pub fn get_num_collapses(&self) -> u32 {
+        self.num_collapses
+    }
+
+    pub fn get_rank_mode(&self) -> RankMode {
+        self.rank_mode
+    }
+
+    pub fn set_rank_mode(&mut self, rank_mode: RankMode) {
+        self.rank_mode = rank_mode;
+    }
+
+    pub fn accept(&mut self, value: f64) {
+        self.accept_with_count(value, 1.0);
+    }
+
+    /// Adds `value` with `count`, ignoring `NaN` values and counts that are not positive and
+    /// finite, as the stores do.
+    pub fn accept_with_count(&mut self, value: f64, count: f64) {
+        if !is_valid_count(count) || value.is_nan() {
+            return;
+        }
+        let min_indexed_value = f64::max(0.0, self.index_mapping.min_indexable_value());
+        let max_indexed_value = self.index_mapping.max_indexable_value();
+        if value < -max_indexed_value || value > max_indexed_value {
+            return;
+        }
+
+        if value > min_indexed_value {
+            self.positive_value_store
+                .add(self.index_mapping.index(value), count);
+        } else if value < -min_indexed_value {
+            self.negative_value_store
+                .add(self.index_mapping.index(-value), count);
+        } else {
+            self.zero_count += count;
+            return;
+        }
+
+        while self.num_bins() > self.max_num_bins {
+            self.collapse();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zero_count == 0.0
+            && self.negative_value_store.is_empty()
+            && self.positive_value_store.is_empty()
+    }
+
+    pub fn get_count(&mut self) -> f64 {
+        self.zero_count
+            + self.negative_value_store.get_total_count()
+            + self.positive_value_store.get_total_count()
+    }
+
+    /// Walks the bins like `DDSketch::get_value_at_quantile`, with the same [`RankMode`].
+    pub fn get_value_at_quantile(&mut self, quantile: f64) -> Option<f64> {
+        let count = self.get_count();
+        let (bin, _) = find_quantile_bin(
+            self.rank_mode,
+            quantile,
+            count,
+            descending_bins(&self.negative_value_store),
+            self.zero_count,
+            ascending_bins(&self.positive_value_store),
+        )?;
+        Some(match bin {
+            QuantileBin::Negative(index) => -self.index_mapping.value(index),
+            QuantileBin::Zero => 0.0,
+            QuantileBin::Positive(index) => self.index_mapping.value(index),
+        })
+    }
+
+    fn num_bins(&self) -> i64 {
+        let span = |store: &UnboundedSizeDenseStore| {
+            if store.is_empty() {
+                0
+            } else {
+                store.get_max_index() as i64 - store.get_min_index() as i64 + 1
+            }
+        };
+        span(&self.negative_value_store) + span(&self.positive_value_store)
+    }
+
+    /// Squares `gamma`. With a zero index offset, a value of index `i` has index `floor(i / 2)`
+    /// under the squared `gamma`, so existing bins are re-binned exactly.
+    fn collapse(&mut self) {
+        let gamma = self.index_mapping.gamma();
+        self.index_mapping = LogarithmicMapping::with_gamma_offset(gamma * gamma, 0.0)
+            .expect("squaring gamma keeps a valid mapping");
+        UDDSketch::collapse_store(&mut self.negative_value_store);
+        UDDSketch::collapse_store(&mut self.positive_value_store);
+        self.num_collapses += 1;
+    }
+
+    fn collapse_store(store: &mut UnboundedSizeDenseStore) {
+        let bins: Vec<(i32, f64)> = store
+            .get_ascending_stream()
+            .into_iter()
+            .map(|(index, count)| (index.div_euclid(2), count))
+            .collect();
+        store.clear();
+        store.compact();
+        store.add_bins_sorted(&bins);
+    }
+}
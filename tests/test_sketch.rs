@@ -1,7 +1,10 @@
-use sketches_rust::DDSketch;
+use sketches_rust::IndexMapping;
+use sketches_rust::Store;
+use sketches_rust::{append_proto, split_protos, ClampPolicy, DDSketch, DurationUnit, Error};
 use sketches_rust::{
     CollapsingHighestDenseStore, CollapsingLowestDenseStore, CubicallyInterpolatedMapping,
-    LogarithmicMapping, UnboundedSizeDenseStore,
+    LogarithmicMapping, RankMode, SketchAggregator, SketchDigest, UDDSketch,
+    UnboundedSizeDenseStore,
 };
 
 #[test]
@@ -257,3 +260,1205 @@ fn test_sketch_create() {
         DDSketch::logarithmic_unbounded_size_dense_store(2e-2).unwrap();
     sketch6.accept(1.0);
 }
+
+#[test]
+fn test_sketch_approx_eq() {
+    let values: Vec<f64> = (1..1000).map(|i| i as f64 * 1.5).collect();
+    let mut sketch1 = DDSketch::unbounded_dense(2e-2).unwrap();
+    for v in values.iter() {
+        sketch1.accept(*v);
+    }
+    let mut sketch2 = DDSketch::collapsing_lowest_dense(2e-2, 2048).unwrap();
+    for i in 0..values.len() {
+        sketch2.accept(values[(i * 7) % values.len()]);
+    }
+    let quantiles = [0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0];
+    assert!(sketch1.approx_eq(&mut sketch2, &quantiles, 1e-9));
+
+    let mut sketch3 = DDSketch::unbounded_dense(2e-2).unwrap();
+    for v in values.iter() {
+        sketch3.accept(*v * 2.0);
+    }
+    assert!(!sketch1.approx_eq(&mut sketch3, &quantiles, 2e-2));
+    assert!(!sketch1.approx_eq(
+        &mut DDSketch::unbounded_dense(2e-2).unwrap(),
+        &quantiles,
+        2e-2
+    ));
+}
+
+#[test]
+fn test_sketch_encode_order() {
+    // Encoded by sketches-java: mapping followed by a positive store.
+    let java = vec![
+        14, 100, 244, 7, 173, 131, 165, 240, 63, 0, 0, 0, 0, 0, 0, 0, 0, 5, 10, 7, 2, 18, 2, 38, 2,
+        2, 4, 4, 2, 4, 2, 12, 3, 6, 2, 2, 2, 12, 140, 100,
+    ];
+    let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+    sketch.decode_and_merge_with(java.clone()).unwrap();
+    assert_eq!(java, sketch.encode().unwrap());
+
+    // The zero count goes right after the index mapping, ahead of both stores.
+    let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+    sketch.accept(-2.0);
+    sketch.accept(-1.0);
+    sketch.accept(0.0);
+    sketch.accept(0.0);
+    sketch.accept(1.0);
+    sketch.accept(2.0);
+    let expected = vec![
+        14, 100, 244, 7, 173, 131, 165, 240, 63, 0, 0, 0, 0, 0, 0, 0, 0, 4, 3, 5, 2, 0, 2, 34, 2,
+        7, 2, 0, 2, 34, 2,
+    ];
+    assert_eq!(expected, sketch.encode().unwrap());
+
+    let mut decoded = DDSketch::unbounded_dense(2e-2).unwrap();
+    decoded.decode_and_merge_with(expected).unwrap();
+    assert_eq!(6.0, decoded.get_count());
+    assert_eq!(Some(0.0), decoded.get_value_at_quantile(0.5));
+}
+
+#[test]
+fn test_sketch_aggregator() {
+    let mut reference = DDSketch::unbounded_dense(2e-2).unwrap();
+    let mut aggregator = SketchAggregator::new(DDSketch::unbounded_dense(2e-2).unwrap());
+    for shard in 0..50 {
+        let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+        for i in 0..200 {
+            let value = (shard * 200 + i) as f64 - 3000.0;
+            sketch.accept(value);
+            reference.accept(value);
+        }
+        aggregator.feed(&sketch.encode().unwrap()).unwrap();
+    }
+    assert_eq!(10000.0, aggregator.get_count());
+    for quantile in [0.0, 0.01, 0.25, 0.5, 0.75, 0.99, 1.0] {
+        assert_eq!(
+            reference.get_value_at_quantile(quantile),
+            aggregator.quantile(quantile)
+        );
+    }
+    assert!(aggregator.feed(&[14, 100]).is_err());
+}
+
+#[test]
+fn test_sketch_freeze() {
+    let mut sketch = DDSketch::collapsing_lowest_dense(1e-2, 1024).unwrap();
+    for i in -500..1500 {
+        sketch.accept(i as f64 * 0.37);
+    }
+    sketch.accept(0.0);
+    let frozen = sketch.freeze();
+    assert_eq!(sketch.get_count(), frozen.get_count());
+    for i in 0..=1000 {
+        let quantile = i as f64 / 1000.0;
+        assert_eq!(
+            sketch.get_value_at_quantile(quantile),
+            frozen.get_value_at_quantile(quantile)
+        );
+    }
+    assert_eq!(None, frozen.get_value_at_quantile(1.5));
+
+    sketch.set_rank_mode(RankMode::QTimesN);
+    let frozen = sketch.freeze();
+    for i in 0..=1000 {
+        let quantile = i as f64 / 1000.0;
+        assert_eq!(
+            sketch.get_value_at_quantile(quantile),
+            frozen.get_value_at_quantile(quantile)
+        );
+    }
+    let frozen = DDSketch::unbounded_dense(1e-2).unwrap().freeze();
+    assert_eq!(None, frozen.get_value_at_quantile(0.5));
+}
+
+#[test]
+fn test_sketch_content_hash() {
+    let mut sketch1 = DDSketch::unbounded_dense(2e-2).unwrap();
+    for i in -100..100 {
+        sketch1.accept(i as f64);
+    }
+    let mut sketch2 = DDSketch::collapsing_highest_dense(2e-2, 1000).unwrap();
+    for i in (-100..100).rev() {
+        sketch2.accept(i as f64);
+    }
+    let mut sketch3 = DDSketch::unbounded_dense(2e-2).unwrap();
+    sketch3
+        .decode_and_merge_with(sketch1.encode().unwrap())
+        .unwrap();
+    assert_eq!(sketch1.content_hash(), sketch2.content_hash());
+    assert_eq!(sketch1.content_hash(), sketch3.content_hash());
+
+    sketch3.accept(42.0);
+    assert_ne!(sketch1.content_hash(), sketch3.content_hash());
+    let mut sketch4 = DDSketch::logarithmic_unbounded_size_dense_store(2e-2).unwrap();
+    for i in -100..100 {
+        sketch4.accept(i as f64);
+    }
+    assert_ne!(sketch1.content_hash(), sketch4.content_hash());
+}
+
+#[test]
+fn test_sketch_rank_mode() {
+    let mapping = CubicallyInterpolatedMapping::with_relative_accuracy(1e-2).unwrap();
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 1..=10 {
+        sketch.accept(i as f64 * 10.0);
+    }
+    let value = |v: f64| Some(mapping.value(mapping.index(v)));
+    assert_eq!(RankMode::QTimesNMinus1, sketch.get_rank_mode());
+    let expected = [
+        (0.0, 10.0),
+        (0.3, 30.0),
+        (0.5, 50.0),
+        (0.95, 90.0),
+        (1.0, 100.0),
+    ];
+    for (quantile, v) in expected {
+        assert_eq!(value(v), sketch.get_value_at_quantile(quantile));
+    }
+
+    sketch.set_rank_mode(RankMode::QTimesN);
+    let expected = [
+        (0.0, 10.0),
+        (0.3, 30.0),
+        (0.5, 50.0),
+        (0.95, 100.0),
+        (1.0, 100.0),
+    ];
+    for (quantile, v) in expected {
+        assert_eq!(value(v), sketch.get_value_at_quantile(quantile));
+    }
+}
+
+#[test]
+fn test_sketch_rank_mode_java_fixture() {
+    // Encoded by sketches-java; its bins are at indexes -4, 5, 24, 25 (3), 27, 29, 35 (2), 38,
+    // 39 and 45 (88). The expected indexes follow DDSketch.getValueAtQuantile in sketches-java.
+    let input = vec![
+        14, 100, 244, 7, 173, 131, 165, 240, 63, 0, 0, 0, 0, 0, 0, 0, 0, 5, 10, 7, 2, 18, 2, 38, 2,
+        2, 4, 4, 2, 4, 2, 12, 3, 6, 2, 2, 2, 12, 140, 100,
+    ];
+    let mapping = CubicallyInterpolatedMapping::with_relative_accuracy(2e-2).unwrap();
+    let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+    sketch.decode_and_merge_with(input).unwrap();
+    assert_eq!(RankMode::QTimesNMinus1, sketch.get_rank_mode());
+    let expected = [
+        (0.0, -4),
+        (0.0101, -4),
+        (0.02, 5),
+        (0.03, 24),
+        (0.06, 25),
+        (0.1, 35),
+        (0.1205, 39),
+        (0.5, 45),
+        (1.0, 45),
+    ];
+    for (quantile, index) in expected {
+        assert_eq!(
+            Some(mapping.value(index)),
+            sketch.get_value_at_quantile(quantile)
+        );
+    }
+
+    // The ranks of q * count differ from sketches-java right below a bin boundary.
+    sketch.set_rank_mode(RankMode::QTimesN);
+    assert_eq!(Some(mapping.value(5)), sketch.get_value_at_quantile(0.0101));
+    assert_eq!(
+        Some(mapping.value(45)),
+        sketch.get_value_at_quantile(0.1205)
+    );
+}
+
+#[test]
+fn test_sketch_zero_bin() {
+    let mut sketch = DDSketch::collapsing_lowest_dense(2e-2, 100).unwrap();
+    assert_eq!(None, sketch.get_rank(0.0));
+    for i in 1..=10 {
+        sketch.accept(-i as f64);
+        sketch.accept(i as f64);
+    }
+    for _ in 0..60 {
+        sketch.accept(0.0);
+    }
+    assert_eq!(Some(0.0), sketch.get_value_at_quantile(0.5));
+    assert_eq!(Some(0.0), sketch.get_value_at_quantile(0.13));
+    assert_eq!(Some(0.0), sketch.get_value_at_quantile(0.86));
+    assert!(sketch.get_value_at_quantile(0.12).unwrap() < 0.0);
+    assert!(sketch.get_value_at_quantile(0.89).unwrap() > 0.0);
+
+    assert_eq!(Some(0.5), sketch.get_rank(0.0));
+    assert_eq!(Some(0.5 / 80.0), sketch.get_rank(-10.0));
+    assert_eq!(Some(79.5 / 80.0), sketch.get_rank(10.0));
+    assert_eq!(Some(0.0), sketch.get_rank(-1000.0));
+    assert_eq!(Some(1.0), sketch.get_rank(1000.0));
+
+    sketch.accept(-20.0);
+    sketch.accept(-30.0);
+    assert_eq!(Some(42.0 / 82.0), sketch.get_rank(0.0));
+}
+
+#[test]
+fn test_sketch_merge_owned() {
+    let build = |from: i32, to: i32| {
+        let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+        for i in from..to {
+            sketch.accept(i as f64);
+        }
+        sketch
+    };
+
+    let mut small = build(1, 5);
+    small.merge_owned(build(-300, 3000)).unwrap();
+
+    let mut large = build(-300, 3000);
+    large.merge_owned(build(1, 5)).unwrap();
+    assert_eq!(small.encode().unwrap(), large.encode().unwrap());
+    assert_eq!(3304.0, small.get_count());
+
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    assert!(sketch.merge_owned(build(1, 5)).is_err());
+
+    let mut narrow = DDSketch::collapsing_lowest_dense(2e-2, 64).unwrap();
+    narrow.accept(1.0);
+    let mut wide = DDSketch::collapsing_lowest_dense(2e-2, 2048).unwrap();
+    for i in 1..100_000 {
+        wide.accept(i as f64);
+    }
+    assert!(wide.iter_bins().count() > 64);
+    narrow.merge_owned(wide).unwrap();
+    assert_eq!(100_000.0, narrow.get_count());
+    assert!(narrow.iter_bins().count() <= 64);
+    for i in 1..10 {
+        narrow.accept(i as f64 * 1e6);
+    }
+    assert!(narrow.iter_bins().count() <= 64);
+}
+
+#[test]
+fn test_sketch_clone_empty() {
+    let mut sketch = DDSketch::logarithmic_collapsing_highest_dense(1e-2, 20).unwrap();
+    sketch.set_rank_mode(RankMode::QTimesN);
+    for i in 1..1000 {
+        sketch.accept(i as f64);
+    }
+    let mut empty: DDSketch<LogarithmicMapping, CollapsingHighestDenseStore> = sketch.clone_empty();
+    assert!(empty.is_empty());
+    assert_eq!(0.0, empty.get_count());
+    assert_eq!(RankMode::QTimesN, empty.get_rank_mode());
+
+    empty
+        .decode_and_merge_with(sketch.encode().unwrap())
+        .unwrap();
+    assert_eq!(sketch.get_count(), empty.get_count());
+    for i in 1000..2000 {
+        sketch.accept(i as f64);
+        empty.accept(i as f64);
+    }
+    assert_eq!(sketch.encode().unwrap(), empty.encode().unwrap());
+}
+
+#[test]
+fn test_sketch_merge_weighted() {
+    let mut sketch1 = DDSketch::unbounded_dense(2e-2).unwrap();
+    let mut expected = DDSketch::unbounded_dense(2e-2).unwrap();
+    for i in -50..50 {
+        sketch1.accept(i as f64);
+        expected.accept(i as f64);
+    }
+    let mut sketch2 = DDSketch::collapsing_lowest_dense(2e-2, 1000).unwrap();
+    for i in 0..200 {
+        sketch2.accept(i as f64 * 0.5);
+        expected.accept(i as f64 * 0.5);
+        expected.accept(i as f64 * 0.5);
+    }
+    sketch1.merge_weighted(&mut sketch2, 2.0).unwrap();
+    assert_eq!(500.0, sketch1.get_count());
+    assert_eq!(expected.encode().unwrap(), sketch1.encode().unwrap());
+
+    assert!(sketch1.merge_weighted(&mut sketch2, 0.0).is_err());
+    assert!(sketch1.merge_weighted(&mut sketch2, -1.0).is_err());
+    assert!(sketch1.merge_weighted(&mut sketch2, f64::NAN).is_err());
+    assert!(sketch1.merge_weighted(&mut sketch2, f64::INFINITY).is_err());
+    assert_eq!(500.0, sketch1.get_count());
+}
+
+#[test]
+fn test_sketch_clamp_policy() {
+    let mut sketch = DDSketch::logarithmic_unbounded_size_dense_store(2e-2).unwrap();
+    assert_eq!(ClampPolicy::Drop, sketch.get_clamp_policy());
+    sketch.accept(1.0);
+    sketch.accept(f64::MAX);
+    assert!(sketch.try_accept(-f64::MAX).is_ok());
+    assert_eq!(1.0, sketch.get_count());
+
+    sketch.set_clamp_policy(ClampPolicy::ClampToEdge);
+    sketch.accept(f64::MAX);
+    sketch.accept_with_count(-f64::MAX, 2.0);
+    assert_eq!(4.0, sketch.get_count());
+    let max = sketch.get_max().unwrap();
+    assert!(max > 1e307 && max.is_finite());
+    assert_eq!(Some(-max), sketch.get_min());
+
+    sketch.set_clamp_policy(ClampPolicy::Error);
+    assert!(sketch.try_accept(f64::MAX).is_err());
+    assert!(sketch.try_accept(-f64::MAX).is_err());
+    assert!(sketch.try_accept(1e300).is_ok());
+    sketch.accept(f64::MAX);
+    assert_eq!(5.0, sketch.get_count());
+}
+
+#[test]
+fn test_sketch_append_proto() {
+    let mut buf = Vec::new();
+    let mut sketches = Vec::new();
+    for n in 1..=3 {
+        let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+        for i in 0..n * 10 {
+            sketch.accept(i as f64 - 5.0);
+        }
+        append_proto(&mut buf, &sketch).unwrap();
+        sketches.push(sketch);
+    }
+
+    let protos = split_protos(&buf).unwrap();
+    assert_eq!(3, protos.len());
+    for (proto, sketch) in protos.iter().zip(sketches.iter_mut()) {
+        assert_eq!(sketch.encode().unwrap(), proto.to_vec());
+        let mut decoded = DDSketch::unbounded_dense(2e-2).unwrap();
+        decoded.decode_and_merge_with(proto.to_vec()).unwrap();
+        assert_eq!(sketch.get_count(), decoded.get_count());
+    }
+
+    assert!(split_protos(&buf[..buf.len() - 1]).is_err());
+    assert!(split_protos(&[1, 0]).is_err());
+    assert!(split_protos(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_sketch_quantile_errors() {
+    let mut coarse = DDSketch::collapsing_lowest_dense(5e-2, 2048).unwrap();
+    let mut fine = DDSketch::unbounded_dense(1e-3).unwrap();
+    for i in -1000..5000 {
+        let value = i as f64 * 0.73;
+        coarse.accept(value);
+        fine.accept(value);
+    }
+    let quantiles = [0.0, 0.01, 0.1, 0.5, 0.9, 0.99, 1.0];
+    let errors = coarse.quantile_errors(&mut fine, &quantiles);
+    assert_eq!(quantiles.len(), errors.len());
+    for error in errors {
+        assert!((0.0..=5e-2 + 1e-3).contains(&error), "{}", error);
+    }
+    let mut empty = DDSketch::unbounded_dense(1e-3).unwrap();
+    let errors = fine.quantile_errors(&mut empty, &[0.5, 0.9]);
+    assert!(errors.iter().all(|error| error.is_nan()));
+}
+
+#[test]
+fn test_uddsketch_uniform_error() {
+    let mut sketch = UDDSketch::new(1e-3, 128).unwrap();
+    let mut values = Vec::new();
+    for i in 1..=20000 {
+        let value = 1.0005f64.powi(i) * 0.01;
+        values.push(value);
+        sketch.accept(value);
+    }
+    assert!(sketch.get_num_collapses() > 0);
+    assert!(sketch.get_relative_accuracy() > 1e-3);
+    assert_eq!(values.len() as f64, sketch.get_count());
+
+    let relative_accuracy = sketch.get_relative_accuracy();
+    for i in 0..=100 {
+        let quantile = i as f64 / 100.0;
+        let expected = values[(quantile * (values.len() - 1) as f64) as usize];
+        let actual = sketch.get_value_at_quantile(quantile).unwrap();
+        let error = (actual - expected).abs() / expected;
+        assert!(
+            error <= relative_accuracy + 1e-9,
+            "quantile {}: error {} exceeds {}",
+            quantile,
+            error,
+            relative_accuracy
+        );
+    }
+    assert!(UDDSketch::new(1e-3, 1).is_err());
+
+    let mut sketch = UDDSketch::new(1e-2, 128).unwrap();
+    sketch.accept(1.0);
+    for count in [f64::NAN, f64::INFINITY, -1.0, 0.0] {
+        sketch.accept_with_count(0.0, count);
+        sketch.accept_with_count(2.0, count);
+    }
+    assert_eq!(1.0, sketch.get_count());
+    assert!(sketch.get_value_at_quantile(1.0).unwrap().is_finite());
+}
+
+#[test]
+fn test_uddsketch_rank_mode() {
+    let mut sketch = UDDSketch::new(1e-2, 4096).unwrap();
+    let mut reference = DDSketch::logarithmic_unbounded_size_dense_store(1e-2).unwrap();
+    for i in -300..700 {
+        // Fractional counts keep the cumulative count below the total at the last bin.
+        sketch.accept_with_count(i as f64 * 0.37, 0.1);
+        reference.accept_with_count(i as f64 * 0.37, 0.1);
+    }
+    assert_eq!(0, sketch.get_num_collapses());
+    assert_eq!(RankMode::QTimesNMinus1, sketch.get_rank_mode());
+    for rank_mode in [RankMode::QTimesN, RankMode::QTimesNMinus1] {
+        sketch.set_rank_mode(rank_mode);
+        reference.set_rank_mode(rank_mode);
+        for i in 0..=1000 {
+            let quantile = i as f64 / 1000.0;
+            assert_eq!(
+                reference.get_value_at_quantile(quantile),
+                sketch.get_value_at_quantile(quantile)
+            );
+        }
+        assert_eq!(reference.get_max(), sketch.get_value_at_quantile(1.0));
+    }
+}
+
+#[test]
+fn test_sketch_get_value_and_count_at_quantile() {
+    let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+    let mut reference = UnboundedSizeDenseStore::new();
+    let index_mapping = CubicallyInterpolatedMapping::with_relative_accuracy(2e-2).unwrap();
+    for i in 1..=50 {
+        let value = (i * i) as f64;
+        sketch.accept_with_count(value, i as f64);
+        reference.add(index_mapping.index(value), i as f64);
+    }
+    sketch.accept_with_count(0.0, 7.0);
+    sketch.accept_with_count(-3.0, 2.0);
+
+    assert_eq!(
+        Some((-index_mapping.value(index_mapping.index(3.0)), 2.0)),
+        sketch.get_value_and_count_at_quantile(0.0)
+    );
+    assert_eq!(
+        Some((0.0, 7.0)),
+        sketch.get_value_and_count_at_quantile(0.005)
+    );
+    for quantile in [0.1, 0.25, 0.5, 0.9, 1.0] {
+        let (value, count) = sketch.get_value_and_count_at_quantile(quantile).unwrap();
+        assert_eq!(sketch.get_value_at_quantile(quantile), Some(value));
+        let index = index_mapping.index(value);
+        assert_eq!(reference.get_count(index - reference.get_offset()), count);
+    }
+    assert_eq!(None, sketch.get_value_and_count_at_quantile(1.5));
+}
+
+#[test]
+fn test_sketch_insert_many() {
+    let values: Vec<f64> = (-2000..3000)
+        .map(|i| if i % 7 == 0 { 0.0 } else { i as f64 * 1.37 })
+        .chain([f64::MAX, -f64::MAX])
+        .collect();
+    let mut bulk = DDSketch::collapsing_lowest_dense(1e-2, 512).unwrap();
+    bulk.insert_many(&values);
+    let mut single = DDSketch::collapsing_lowest_dense(1e-2, 512).unwrap();
+    for value in &values {
+        single.accept(*value);
+    }
+    assert_eq!(single.get_count(), bulk.get_count());
+    for quantile in [0.0, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0] {
+        assert_eq!(
+            single.get_value_at_quantile(quantile),
+            bulk.get_value_at_quantile(quantile)
+        );
+    }
+    assert_eq!(single.encode().unwrap(), bulk.encode().unwrap());
+}
+
+#[test]
+fn test_sketch_special_values() {
+    for clamp_policy in [
+        ClampPolicy::Drop,
+        ClampPolicy::ClampToEdge,
+        ClampPolicy::Error,
+    ] {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        sketch.set_clamp_policy(clamp_policy);
+        sketch.accept(1.0);
+        sketch.accept(2.0);
+        assert!(sketch.try_accept(f64::NAN).is_err());
+        sketch.accept(f64::NAN);
+        sketch.accept_with_count(3.0, f64::NAN);
+        sketch.accept_with_count(0.0, f64::INFINITY);
+        sketch.accept_with_count(3.0, f64::INFINITY);
+        sketch.insert_many(&[f64::NAN, f64::INFINITY, f64::NEG_INFINITY]);
+        sketch.accept(f64::INFINITY);
+        sketch.accept(f64::NEG_INFINITY);
+        let infinity = sketch.try_accept(f64::INFINITY);
+        assert_eq!(clamp_policy == ClampPolicy::Error, infinity.is_err());
+
+        if clamp_policy == ClampPolicy::ClampToEdge {
+            assert_eq!(7.0, sketch.get_count());
+            let max = sketch.get_value_at_quantile(1.0).unwrap();
+            let min = sketch.get_value_at_quantile(0.0).unwrap();
+            assert!(max.is_finite() && max > 1e300);
+            assert!(min.is_finite() && min < -1e300);
+        } else {
+            assert_eq!(2.0, sketch.get_count());
+            assert!(sketch.get_value_at_quantile(0.0).unwrap().is_finite());
+            assert!(sketch.get_value_at_quantile(1.0).unwrap().is_finite());
+            assert!(sketch.get_sum().unwrap().is_finite());
+        }
+        assert!(sketch.get_value_at_quantile(0.5).unwrap().is_finite());
+    }
+}
+
+#[test]
+fn test_sketch_quantile_band() {
+    let mut sketch = DDSketch::logarithmic_unbounded_size_dense_store(2e-2).unwrap();
+    let mut values: Vec<f64> = (0..5000)
+        .map(|i| ((i * 7919) % 5000) as f64 * 0.31 - 400.0)
+        .collect();
+    for value in &values {
+        sketch.accept(*value);
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    for i in 0..=100 {
+        let quantile = i as f64 / 100.0;
+        let expected = values[(quantile * (values.len() - 1) as f64) as usize];
+        let (lower, estimate, upper) = sketch.quantile_band(quantile).unwrap();
+        assert_eq!(sketch.get_value_at_quantile(quantile), Some(estimate));
+        assert!(lower <= upper);
+        assert!(
+            lower - 1e-9 <= expected && expected <= upper + 1e-9,
+            "{} not in [{}, {}]",
+            expected,
+            lower,
+            upper
+        );
+    }
+    assert_eq!(None, sketch.quantile_band(-0.1));
+}
+
+#[test]
+fn test_sketch_merge_same_store_type() {
+    let mut other = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 1..2000 {
+        other.accept(i as f64 * 3.1);
+        other.accept(-(i as f64) * 0.2);
+    }
+    other.accept(0.0);
+
+    let mut same = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut different = DDSketch::collapsing_lowest_dense(1e-2, 4096).unwrap();
+    for value in [1e-3, 5e6] {
+        same.accept(value);
+        different.accept(value);
+    }
+    same.merge_with(&mut other).unwrap();
+    different.merge_with(&mut other).unwrap();
+
+    assert_eq!(different.get_count(), same.get_count());
+    assert_eq!(different.encode().unwrap(), same.encode().unwrap());
+    let mut wrong = DDSketch::unbounded_dense(2e-2).unwrap();
+    assert!(same.merge_with(&mut wrong).is_err());
+}
+
+#[test]
+fn test_sketch_relative_accuracy() {
+    for relative_accuracy in [1e-3, 1e-2, 2e-2, 5e-2] {
+        // The mappings derive the accuracy back from gamma, which may round the last digits.
+        let sketch = DDSketch::logarithmic_unbounded_size_dense_store(relative_accuracy).unwrap();
+        let mapping = LogarithmicMapping::with_relative_accuracy(relative_accuracy).unwrap();
+        assert_eq!(
+            mapping.get_relative_accuracy(),
+            sketch.get_relative_accuracy()
+        );
+        assert!((sketch.get_relative_accuracy() - relative_accuracy).abs() < 1e-15);
+
+        let sketch = DDSketch::collapsing_lowest_dense(relative_accuracy, 128).unwrap();
+        let mapping =
+            CubicallyInterpolatedMapping::with_relative_accuracy(relative_accuracy).unwrap();
+        assert_eq!(
+            mapping.get_relative_accuracy(),
+            sketch.get_relative_accuracy()
+        );
+        assert!((sketch.get_relative_accuracy() - relative_accuracy).abs() < 1e-15);
+    }
+}
+
+#[test]
+fn test_sketch_from_histogram_buckets() {
+    let buckets = [
+        (1.0, 10.0),
+        (2.0, 20.0),
+        (5.0, 30.0),
+        (10.0, 40.0),
+        (f64::INFINITY, 5.0),
+    ];
+    let mut sketch = DDSketch::from_histogram_buckets(&buckets, 1e-2).unwrap();
+    assert_eq!(105.0, sketch.get_count());
+    assert_relative_eq(0.5, sketch.get_value_at_quantile(0.0).unwrap());
+    assert_relative_eq(3.5, sketch.get_value_at_quantile(0.5).unwrap());
+    assert_relative_eq(7.5, sketch.get_value_at_quantile(0.9).unwrap());
+    assert_relative_eq(10.0, sketch.get_value_at_quantile(1.0).unwrap());
+
+    let empty = DDSketch::from_histogram_buckets(&[], 1e-2).unwrap();
+    assert!(empty.is_empty());
+    let mut negative = DDSketch::from_histogram_buckets(&[(-1.0, 2.0), (1.0, 2.0)], 1e-2).unwrap();
+    assert_relative_eq(-1.0, negative.get_min().unwrap());
+    assert_eq!(Some(0.0), negative.get_max());
+    assert!(DDSketch::from_histogram_buckets(&[(2.0, 1.0), (1.0, 1.0)], 1e-2).is_err());
+    assert!(DDSketch::from_histogram_buckets(&[(1.0, -1.0)], 1e-2).is_err());
+    assert!(DDSketch::from_histogram_buckets(&[(1.0, f64::INFINITY)], 1e-2).is_err());
+}
+
+fn assert_relative_eq(expected: f64, actual: f64) {
+    assert!(
+        (actual - expected).abs() <= expected.abs() * 2e-2,
+        "{} is not {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn test_sketch_count_near() {
+    let mut sketch = DDSketch::logarithmic_collapsing_highest_dense(1e-2, 1024).unwrap();
+    for _ in 0..25 {
+        sketch.accept(42.0);
+        sketch.accept(-3.5);
+    }
+    sketch.accept(42.1);
+    sketch.accept_with_count(0.0, 4.0);
+
+    assert_eq!(26.0, sketch.count_near(42.0));
+    assert_eq!(26.0, sketch.count_near(42.05));
+    assert_eq!(25.0, sketch.count_near(-3.5));
+    assert_eq!(4.0, sketch.count_near(0.0));
+    assert_eq!(0.0, sketch.count_near(50.0));
+    assert_eq!(0.0, sketch.count_near(1e9));
+    assert_eq!(0.0, sketch.count_near(f64::NAN));
+}
+
+#[test]
+fn test_sketch_iter_bins() {
+    let mut sketch = DDSketch::unbounded_dense(5e-2).unwrap();
+    let mut value = 1.0;
+    while value < 100.0 {
+        sketch.accept(value);
+        sketch.accept(-value);
+        value *= 1.01;
+    }
+    sketch.accept(0.0);
+
+    let bins: Vec<(f64, f64, f64)> = sketch.iter_bins().collect();
+    assert_eq!(
+        sketch.get_count(),
+        bins.iter().map(|bin| bin.2).sum::<f64>()
+    );
+    assert!(bins.iter().all(|bin| bin.0 < bin.1));
+    assert!(bins.iter().any(|bin| bin.0 < 0.0 && bin.1 > 0.0));
+    let mut adjacent = 0;
+    for pair in bins.windows(2) {
+        assert!(pair[0].1 <= pair[1].0);
+        if pair[0].1 == pair[1].0 {
+            adjacent += 1;
+        }
+    }
+    // Every bin but the ones around zero is next to the following one.
+    assert_eq!(bins.len() - 3, adjacent);
+}
+
+#[test]
+fn test_sketch_digest() {
+    let mut sketch = DDSketch::logarithmic_collapsing_lowest_dense(1e-2, 2048).unwrap();
+    for i in -100..900 {
+        sketch.accept(i as f64 * 0.5);
+    }
+    let quantiles = [0.0, 0.5, 0.9, 0.99, 1.0];
+    let digest = sketch.digest(&quantiles);
+    assert_eq!(sketch.get_count(), digest.get_count());
+    assert_eq!(sketch.get_min(), digest.get_min());
+    assert_eq!(sketch.get_max(), digest.get_max());
+    assert_eq!(sketch.get_sum(), digest.get_sum());
+    for quantile in quantiles {
+        assert_eq!(
+            sketch.get_value_at_quantile(quantile),
+            digest.get_value_at_quantile(quantile)
+        );
+    }
+    assert_eq!(None, digest.get_value_at_quantile(0.25));
+
+    let bytes = digest.encode().unwrap();
+    assert_eq!(4 * 8 + 1 + quantiles.len() * 16, bytes.len());
+    assert_eq!(digest, SketchDigest::decode(bytes).unwrap());
+
+    let empty = DDSketch::unbounded_dense(1e-2).unwrap().digest(&[0.5]);
+    assert_eq!(0.0, empty.get_count());
+    assert_eq!(None, empty.get_min());
+    assert_eq!(None, empty.get_value_at_quantile(0.5));
+    assert!(SketchDigest::decode(vec![0; 10]).is_err());
+}
+
+#[test]
+fn test_sketch_try_from_bytes() {
+    let mut sketch = DDSketch::unbounded_dense(2e-2).unwrap();
+    for i in -50..200 {
+        sketch.accept(i as f64 * 1.5);
+    }
+    let bytes = sketch.encode().unwrap();
+    let mut decoded =
+        DDSketch::<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>::try_from(&bytes[..])
+            .unwrap();
+    assert_eq!(sketch.get_count(), decoded.get_count());
+    assert_eq!(bytes, decoded.encode().unwrap());
+    assert!(sketch.approx_eq(&mut decoded, &[0.0, 0.5, 0.99, 1.0], 0.0));
+
+    assert!(DDSketch::<LogarithmicMapping, UnboundedSizeDenseStore>::try_from(&bytes[..]).is_err());
+    assert!(
+        DDSketch::<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>::try_from(&bytes[..10])
+            .is_err()
+    );
+    assert!(
+        DDSketch::<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>::try_from(
+            &bytes[..bytes.len() - 1]
+        )
+        .is_err()
+    );
+    assert!(
+        DDSketch::<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>::try_from(&[3, 0, 0][..])
+            .is_err()
+    );
+    assert!(
+        DDSketch::<CubicallyInterpolatedMapping, UnboundedSizeDenseStore>::try_from(&[][..])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_sketch_into_bytes() {
+    let mut sketch = DDSketch::logarithmic_collapsing_lowest_dense(1e-2, 1024).unwrap();
+    for i in -30..300 {
+        sketch.accept(i as f64 * 0.25);
+    }
+    let bytes: Vec<u8> = (&sketch).into();
+    assert_eq!(sketch.encode().unwrap(), bytes);
+
+    let mut decoded = DDSketch::logarithmic_collapsing_lowest_dense(1e-2, 1024).unwrap();
+    decoded.decode_and_merge_with(bytes).unwrap();
+    assert_eq!(sketch.content_hash(), decoded.content_hash());
+    assert!(sketch.approx_eq(&mut decoded, &[0.0, 0.1, 0.5, 0.9, 1.0], 0.0));
+}
+
+#[test]
+fn test_sketch_max_quantile() {
+    let mut sketches = Vec::new();
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 0..1000 {
+        sketch.accept_with_count(i as f64 * 0.37, 0.1);
+    }
+    sketches.push(sketch);
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 0..997 {
+        sketch.accept_with_count(-(i as f64), 0.3);
+        sketch.accept_with_count(0.0, 0.7);
+    }
+    sketches.push(sketch);
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    sketch.accept_with_count(5.0, 1e-300);
+    sketch.accept_with_count(1e6, 1e300);
+    sketches.push(sketch);
+
+    for mut sketch in sketches {
+        for rank_mode in [RankMode::QTimesN, RankMode::QTimesNMinus1] {
+            sketch.set_rank_mode(rank_mode);
+            let max = sketch.get_max();
+            assert!(max.is_some());
+            assert_eq!(max, sketch.get_value_at_quantile(1.0));
+            assert_eq!(max, sketch.freeze().get_value_at_quantile(1.0));
+        }
+    }
+}
+
+#[test]
+fn test_sketch_stochastically_dominates() {
+    let quantiles = [0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0];
+    let mut baseline = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut slower = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut spread = DDSketch::unbounded_dense(1e-2).unwrap();
+    for i in 1..=1000 {
+        baseline.accept(i as f64);
+        slower.accept(i as f64 * 1.5 + 10.0);
+        spread.accept(500.0 + (i as f64 - 500.0) * 3.0);
+    }
+    assert!(slower.stochastically_dominates(&mut baseline, &quantiles));
+    assert!(!baseline.stochastically_dominates(&mut slower, &quantiles));
+
+    // The distributions cross, so neither dominates the other.
+    assert!(!spread.stochastically_dominates(&mut baseline, &quantiles));
+    assert!(!baseline.stochastically_dominates(&mut spread, &quantiles));
+
+    let mut empty = DDSketch::unbounded_dense(1e-2).unwrap();
+    assert!(!baseline.stochastically_dominates(&mut empty, &quantiles));
+}
+
+#[test]
+fn test_sketch_merge_serialized_batch() {
+    let mut blobs = Vec::new();
+    for seed in 0..16 {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        for i in 0..200 {
+            sketch.accept(((i * 37 + seed * 101) % 997) as f64 - 300.0);
+        }
+        sketch.accept_with_count(0.0, seed as f64);
+        blobs.push(sketch.encode().unwrap());
+    }
+
+    let mut sequential = DDSketch::unbounded_dense(1e-2).unwrap();
+    for blob in &blobs {
+        sequential.decode_and_merge_with(blob.clone()).unwrap();
+    }
+    let mut batched = DDSketch::unbounded_dense(1e-2).unwrap();
+    batched.merge_serialized_batch(&blobs).unwrap();
+    let mut reversed = DDSketch::unbounded_dense(1e-2).unwrap();
+    blobs.reverse();
+    reversed.merge_serialized_batch(&blobs).unwrap();
+
+    let quantiles = [0.0, 0.01, 0.25, 0.5, 0.75, 0.99, 1.0];
+    assert_eq!(sequential.get_count(), batched.get_count());
+    assert!(sequential.approx_eq(&mut batched, &quantiles, 0.0));
+    assert!(sequential.approx_eq(&mut reversed, &quantiles, 0.0));
+
+    // A blob that fails to decode leaves the sketch untouched.
+    blobs.push(vec![0xff, 0x01]);
+    assert!(batched.merge_serialized_batch(&blobs).is_err());
+    assert!(sequential.approx_eq(&mut batched, &quantiles, 0.0));
+    assert_eq!(sequential.get_count(), batched.get_count());
+}
+
+#[test]
+fn test_effective_accuracy_at_quantile() {
+    let accuracy = 2e-2;
+    let nominal = |actual: f64| (actual - accuracy).abs() < 1e-12;
+    let mut highest = DDSketch::collapsing_highest_dense(accuracy, 200).unwrap();
+    let mut lowest = DDSketch::collapsing_lowest_dense(accuracy, 200).unwrap();
+    let mut unbounded = DDSketch::unbounded_dense(accuracy).unwrap();
+    // The body spans about 115 bins, the outliers are beyond the bin budget.
+    for value in (0..10_000).map(|i| 1.0 + (i % 100) as f64) {
+        highest.accept(value);
+        lowest.accept(value);
+        unbounded.accept(value);
+    }
+    highest.accept(1e9);
+    lowest.accept(1e-9);
+    unbounded.accept(1e9);
+
+    assert!(highest.effective_accuracy_at_quantile(1.0) > 0.5);
+    assert!(lowest.effective_accuracy_at_quantile(0.0) > 0.5);
+    for quantile in [0.0, 0.5, 1.0] {
+        assert!(nominal(unbounded.effective_accuracy_at_quantile(quantile)));
+    }
+    for quantile in [0.0, 0.01, 0.5, 0.99] {
+        assert!(nominal(highest.effective_accuracy_at_quantile(quantile)));
+    }
+    for quantile in [0.01, 0.5, 0.99, 1.0] {
+        assert!(nominal(lowest.effective_accuracy_at_quantile(quantile)));
+    }
+
+    let mut negated = DDSketch::collapsing_highest_dense(accuracy, 200).unwrap();
+    for value in (0..10_000).map(|i| 1.0 + (i % 100) as f64).chain([1e9]) {
+        negated.accept(-value);
+    }
+    assert!(negated.effective_accuracy_at_quantile(0.0) > 0.5);
+    assert!(nominal(negated.effective_accuracy_at_quantile(0.5)));
+    assert!(nominal(negated.effective_accuracy_at_quantile(1.0)));
+    assert!(nominal(
+        DDSketch::unbounded_dense(accuracy)
+            .unwrap()
+            .effective_accuracy_at_quantile(0.5)
+    ));
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn test_fill_log_normal() {
+    let quantiles = [0.0, 0.1, 0.5, 0.9, 0.99, 1.0];
+    let fill = |seed| {
+        let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+        sketch.fill_log_normal(10_000, 1.0, 0.5, seed);
+        quantiles.map(|quantile| sketch.get_value_at_quantile(quantile).unwrap())
+    };
+    assert_eq!(fill(7), fill(7));
+    assert_ne!(fill(7), fill(8));
+    assert_relative_eq(fill(7)[2], 1f64.exp());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_parallel_quantiles() {
+    let mut sketches: Vec<_> = (0..500)
+        .map(|i| {
+            let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+            for j in 0..(i % 50) {
+                sketch.accept((i * j) as f64 - 1000.0);
+            }
+            sketch
+        })
+        .collect();
+    for quantile in [0.0, 0.5, 0.99, 1.0, 1.5] {
+        let expected: Vec<Option<f64>> = sketches
+            .iter_mut()
+            .map(|sketch| sketch.get_value_at_quantile(quantile))
+            .collect();
+        let actual = sketches_rust::parallel_quantiles(&mut sketches, quantile);
+        assert_eq!(expected, actual);
+    }
+    assert_eq!(
+        None,
+        sketches_rust::parallel_quantiles(&mut sketches, 0.5)[0]
+    );
+}
+
+#[test]
+fn test_sketch_merge_different_zero_thresholds() {
+    let values = [-3.0, -0.5, -0.01, 0.0, 0.2, 0.9, 1.5, 4.0];
+    let mut fine = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut coarse = DDSketch::unbounded_dense(1e-2).unwrap();
+    assert!(coarse.set_zero_threshold(-1.0).is_err());
+    assert!(coarse.set_zero_threshold(f64::NAN).is_err());
+    coarse.set_zero_threshold(1.0).unwrap();
+    assert_eq!(1.0, coarse.get_zero_threshold());
+    for value in values {
+        fine.accept(value);
+        coarse.accept(value);
+    }
+    assert_eq!(Some(0.0), coarse.get_value_at_quantile(0.5));
+    assert!(coarse.set_zero_threshold(0.5).is_err());
+
+    let mut merged = fine.clone_empty();
+    merged.merge_with(&mut fine).unwrap();
+    merged.merge_with(&mut coarse).unwrap();
+    let mut owned = coarse.clone_empty();
+    owned.merge_with(&mut coarse).unwrap();
+    owned.merge_owned(fine).unwrap();
+    for mut sketch in [merged, owned] {
+        assert_eq!(1.0, sketch.get_zero_threshold());
+        assert_eq!(16.0, sketch.get_count());
+        // Everything in [-1, 1] is counted as zero.
+        let bins: Vec<(f64, f64, f64)> = sketch.iter_bins().collect();
+        assert_eq!(4, bins.len());
+        assert_eq!((-1.0, 1.0, 10.0), bins[1]);
+        assert_relative_eq(-3.0, sketch.get_value_at_quantile(0.0).unwrap());
+        assert_eq!(Some(0.0), sketch.get_value_at_quantile(0.5));
+        assert_relative_eq(4.0, sketch.get_value_at_quantile(1.0).unwrap());
+    }
+}
+
+#[test]
+fn test_sketch_add_duration() {
+    use std::time::Duration;
+
+    let durations = [3, 8, 12, 40, 250].map(Duration::from_millis);
+    let mut seconds = DDSketch::unbounded_dense(1e-3).unwrap();
+    let mut millis = DDSketch::unbounded_dense(1e-3).unwrap();
+    millis.set_duration_unit(DurationUnit::Milliseconds);
+    assert_eq!(DurationUnit::Seconds, seconds.get_duration_unit());
+    for duration in durations {
+        seconds.add_duration(duration);
+        millis.add_duration(duration);
+    }
+
+    let median = seconds.get_value_at_quantile(0.5).unwrap();
+    assert_relative_eq(0.012, median);
+    let median = DurationUnit::Seconds.duration_of(median);
+    assert!(median.abs_diff(Duration::from_millis(12)) < Duration::from_micros(24));
+
+    let median = millis.get_value_at_quantile(0.5).unwrap();
+    assert_relative_eq(12.0, median);
+    let median = millis.get_duration_unit().duration_of(median);
+    assert!(median.abs_diff(Duration::from_millis(12)) < Duration::from_micros(24));
+    assert_eq!(
+        Duration::from_micros(1500),
+        DurationUnit::Nanoseconds.duration_of(1.5e6)
+    );
+    assert_eq!(Duration::ZERO, DurationUnit::Microseconds.duration_of(-1.0));
+}
+
+#[test]
+fn test_sketch_timer() {
+    use std::time::Duration;
+
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    sketch.set_duration_unit(DurationUnit::Milliseconds);
+    {
+        let _timer = sketch.time();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(1.0, sketch.get_count());
+    let elapsed = sketch.get_value_at_quantile(0.5).unwrap();
+    assert!((19.8..1000.0).contains(&elapsed), "{}", elapsed);
+
+    std::mem::forget(sketch.time());
+    assert_eq!(1.0, sketch.get_count());
+}
+
+#[test]
+fn test_sketch_quantile_of_union() {
+    let mut a = DDSketch::collapsing_lowest_dense(1e-2, 1024).unwrap();
+    let mut b = DDSketch::collapsing_lowest_dense(1e-2, 1024).unwrap();
+    for i in 0..700 {
+        a.accept((i % 97) as f64 * 1.7 - 40.0);
+        if i % 3 == 0 {
+            b.accept((i % 41) as f64 * -2.3 + 5.0);
+        }
+    }
+    b.accept_with_count(0.0, 12.0);
+
+    let mut merged = a.clone_empty();
+    merged.merge_with(&mut a).unwrap();
+    merged.merge_with(&mut b).unwrap();
+    for quantile in [0.0, 0.001, 0.1, 0.25, 0.5, 0.75, 0.9, 0.999, 1.0] {
+        assert_eq!(
+            merged.get_value_at_quantile(quantile),
+            DDSketch::quantile_of_union(&a, &b, quantile),
+            "{}",
+            quantile
+        );
+    }
+    assert_eq!(None, DDSketch::quantile_of_union(&a, &b, 1.5));
+
+    let empty = a.clone_empty();
+    assert_eq!(
+        a.get_value_at_quantile(0.3),
+        DDSketch::quantile_of_union(&empty, &a, 0.3)
+    );
+    assert_eq!(None, DDSketch::quantile_of_union(&empty, &empty, 0.3));
+    let other_mapping = DDSketch::unbounded_dense(2e-2).unwrap();
+    assert_eq!(None, DDSketch::quantile_of_union(&a, &other_mapping, 0.3));
+}
+
+#[test]
+fn test_sketch_earth_movers_distance() {
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut same = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut shifted = DDSketch::unbounded_dense(1e-2).unwrap();
+    let mut other_mapping = DDSketch::logarithmic_unbounded_size_dense_store(1e-3).unwrap();
+    for i in 0..1000 {
+        let value = (i % 100) as f64 - 20.0;
+        sketch.accept(value);
+        same.accept_with_count(value, 3.0);
+        shifted.accept(value + 10.0);
+        other_mapping.accept(value);
+    }
+    assert_eq!(0.0, sketch.earth_movers_distance(&mut same));
+
+    // Shifting every value by 10 moves all the mass by about 10.
+    let distance = sketch.earth_movers_distance(&mut shifted);
+    assert!((distance - 10.0).abs() < 0.5, "{}", distance);
+    assert_eq!(distance, shifted.earth_movers_distance(&mut sketch));
+
+    let distance = sketch.earth_movers_distance(&mut other_mapping);
+    assert!(distance > 0.0 && distance < 0.5, "{}", distance);
+
+    let mut empty = DDSketch::unbounded_dense(1e-2).unwrap();
+    assert!(sketch.earth_movers_distance(&mut empty).is_nan());
+}
+
+#[test]
+fn test_sketch_collapsing_bin_budget() {
+    let values: Vec<f64> = (0..20_000).map(|i| 1.001f64.powi(i) * 1e-3).collect();
+    let max_value = values[values.len() - 1];
+
+    let mut lowest = DDSketch::collapsing_lowest_dense(1e-2, 128).unwrap();
+    let mut highest = DDSketch::collapsing_highest_dense(1e-2, 128).unwrap();
+    let mut log_lowest = DDSketch::logarithmic_collapsing_lowest_dense(1e-2, 128).unwrap();
+    let mut log_highest = DDSketch::logarithmic_collapsing_highest_dense(1e-2, 128).unwrap();
+    for &value in &values {
+        lowest.accept(value);
+        highest.accept(value);
+        log_lowest.accept(value);
+        log_highest.accept(value);
+    }
+
+    for (bins, count) in [
+        (lowest.iter_bins().count(), lowest.get_count()),
+        (highest.iter_bins().count(), highest.get_count()),
+        (log_lowest.iter_bins().count(), log_lowest.get_count()),
+        (log_highest.iter_bins().count(), log_highest.get_count()),
+    ] {
+        assert!(bins <= 128, "{}", bins);
+        assert_eq!(values.len() as f64, count);
+    }
+
+    // The collapsed end loses accuracy, the other one keeps it.
+    for quantile in [0.95, 0.99, 1.0] {
+        let expected = values[(quantile * (values.len() - 1) as f64) as usize];
+        assert_relative_eq(expected, lowest.get_value_at_quantile(quantile).unwrap());
+        assert_relative_eq(
+            expected,
+            log_lowest.get_value_at_quantile(quantile).unwrap(),
+        );
+    }
+    for quantile in [0.0, 0.01, 0.05] {
+        let expected = values[(quantile * (values.len() - 1) as f64) as usize];
+        assert_relative_eq(expected, highest.get_value_at_quantile(quantile).unwrap());
+        assert_relative_eq(
+            expected,
+            log_highest.get_value_at_quantile(quantile).unwrap(),
+        );
+    }
+    assert!(lowest.get_value_at_quantile(0.0).unwrap() > 2.0 * values[0]);
+    assert!(highest.get_value_at_quantile(1.0).unwrap() < max_value / 2.0);
+}
+
+#[test]
+fn test_sketch_versioned_encoding() {
+    let mut fixture = vec![
+        1, 14, 100, 244, 7, 173, 131, 165, 240, 63, 0, 0, 0, 0, 0, 0, 0, 0, 5, 21, 0, 140, 48, 34,
+        150, 241, 16, 20, 148, 191, 96, 14, 142, 62, 12, 139, 16, 10, 134, 96, 8, 3, 6, 2, 6, 2, 6,
+        2, 4, 2, 42, 2, 26, 2, 6, 2, 20, 2, 6, 2, 2, 2, 10, 2, 20, 2, 14, 2, 10, 2,
+    ];
+    let mut sketch = DDSketch::collapsing_lowest_dense(2e-2, 50).unwrap();
+    sketch
+        .decode_versioned_and_merge_with(fixture.clone())
+        .unwrap();
+    assert_eq!(4538.0, sketch.get_count());
+    let encoded = sketch.encode_versioned().unwrap();
+    assert_eq!(1, encoded[0]);
+    assert_eq!(sketch.encode().unwrap(), encoded[1..]);
+    let mut decoded = sketch.clone_empty();
+    decoded.decode_versioned_and_merge_with(encoded).unwrap();
+    assert!(sketch.approx_eq(&mut decoded, &[0.0, 0.5, 0.99, 1.0], 0.0));
+
+    fixture[0] = 2;
+    let mut other = sketch.clone_empty();
+    assert!(matches!(
+        other.decode_versioned_and_merge_with(fixture),
+        Err(Error::UnsupportedVersion(2))
+    ));
+    assert!(other.is_empty());
+    assert!(other.decode_versioned_and_merge_with(Vec::new()).is_err());
+}
+
+#[test]
+fn test_sketch_add_weighted_values() {
+    let values = [-4.5, 0.0, 1.0, 2.5, 1.0, 1000.0];
+    let weights = [2.0, 0.5, 3.0, 1.25, 1.0, 7.0];
+    let mut weighted = DDSketch::unbounded_dense(1e-2).unwrap();
+    weighted.add_weighted_values(&values, &weights).unwrap();
+    let mut expected = DDSketch::unbounded_dense(1e-2).unwrap();
+    for (&value, &weight) in values.iter().zip(&weights) {
+        expected.accept_with_count(value, weight);
+    }
+    assert_eq!(expected.get_count(), weighted.get_count());
+    assert_eq!(expected.get_sum(), weighted.get_sum());
+    assert!(expected.approx_eq(&mut weighted, &[0.0, 0.1, 0.3, 0.5, 0.7, 1.0], 0.0));
+
+    let mut sketch = DDSketch::unbounded_dense(1e-2).unwrap();
+    assert!(sketch.add_weighted_values(&values, &weights[1..]).is_err());
+    for invalid in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        let mut invalid_weights = weights;
+        invalid_weights[3] = invalid;
+        assert!(sketch
+            .add_weighted_values(&values, &invalid_weights)
+            .is_err());
+    }
+    assert!(sketch.is_empty());
+}